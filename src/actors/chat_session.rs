@@ -1,24 +1,67 @@
-use actix::{Actor, Addr, AsyncContext, Handler, StreamHandler};
+use actix::{Actor, ActorContext, ActorFutureExt, AsyncContext, Handler, StreamHandler, WrapFuture};
+use actix_web::{rt, web};
 use actix_web_actors::ws;
 use tracing::{info, error, debug};
 use crate::libs::ws::{
-    parse_request::WsRequest,
-    ws_sent_message::ServerMessage,
+    parse_request::ClientRequest,
+    protocol::{Protocol, FEATURES},
+    ws_response::{request_id_of, Delivery, WsErrorKind, WsResponse},
+    ws_sent_message::{DisconnectReason, ServerMessage},
+};
+use crate::libs::logging::redact;
+use crate::libs::message::Message;
+use crate::libs::message::signal::Signal;
+use crate::libs::core::{
+    BehaviorAfterReceiveMessage, Core, JoinLineResult, SharedCore, Sender, sender_to_string,
+    ILLEGAL_INPUT, INTERNAL_SERVER_ERROR, EVICTED_BY_ADMIN, KICKED_FROM_LINE, SERVER_RESTARTING, LEFT_LINE,
 };
-use crate::libs::core::{BehaviorAfterReceiveMessage, Core, Sender};
 
 pub(crate) struct WsChatSession {
-    pub(crate) name: Option<String>,
-    core: Core,
-    user_id: Addr<Sender>,
+    sender: Sender,
+    device: String,
+    line_id: u16,
+    /// Requested group size, used only when the session opens the line.
+    max_members: Option<usize>,
+    /// Where the queue is replayed from when the session joins.
+    have_up_to: Option<u64>,
+    protocol: Protocol,
+    core: SharedCore,
+}
+
+impl WsChatSession {
+    pub(crate) fn new(sender: Sender, device: String, line_id: u16, max_members: Option<usize>, have_up_to: Option<u64>, protocol: Protocol, core: SharedCore) -> Self {
+        WsChatSession { sender, device, line_id, max_members, have_up_to, protocol, core }
+    }
 }
 
 impl Actor for WsChatSession {
     type Context = ws::WebsocketContext<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
         // Register self in the shared state of connected clients
-        self.user_id.do_send(self.user_id.clone());
-        // TODO
+        let session = ctx.address().recipient();
+        let (sender, device, line_id, max_members, have_up_to) = (self.sender, self.device.clone(), self.line_id, self.max_members, self.have_up_to);
+        let join = move |core: &Core| core.join_line(sender, &device, line_id, max_members, have_up_to, session);
+        self.with_core(ctx, join, |_, joined, ctx| match joined {
+            Ok(JoinLineResult::BeTheSecond(messages))
+            | Ok(JoinLineResult::JoinTheGroup(messages))
+            | Ok(JoinLineResult::Rejoin(messages)) => {
+                if !messages.is_empty() {
+                    ctx.notify(ServerMessage::PushChatMessages(messages));
+                }
+            }
+            Ok(JoinLineResult::BeTheFirst) => {}
+            Err(e) => {
+                ctx.notify(ServerMessage::Error(e));
+                ctx.close(None);
+                ctx.stop();
+            }
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        let session = ctx.address().recipient();
+        let (core, sender) = (self.core.clone(), self.sender);
+        rt::task::spawn_blocking(move || core.set_offline(sender, &session));
     }
 }
 
@@ -26,7 +69,28 @@ impl Handler<ServerMessage> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: ServerMessage, ctx: &mut Self::Context) -> Self::Result {
-        match serde_json::to_string(&msg) {
+        match msg {
+            ServerMessage::Disconnect(reason) => {
+                let (code, description) = match reason {
+                    DisconnectReason::EvictedByAdmin => (ws::CloseCode::Policy, EVICTED_BY_ADMIN),
+                    DisconnectReason::Kicked => (ws::CloseCode::Policy, KICKED_FROM_LINE),
+                    DisconnectReason::ServerRestarting => (ws::CloseCode::Restart, SERVER_RESTARTING),
+                    DisconnectReason::LeftLine => (ws::CloseCode::Normal, LEFT_LINE),
+                };
+                ctx.close(Some(ws::CloseReason {
+                    code,
+                    description: Some(description.to_string()),
+                }));
+                ctx.stop();
+            }
+            msg => self.send_frame(&msg, ctx),
+        }
+    }
+} // impl Handler<ServerMessage> for WsChatSession
+
+impl WsChatSession {
+    fn send_frame(&self, msg: &ServerMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        match serde_json::to_string(msg) {
             Ok(msg) => {
                 ctx.text(msg);
             }
@@ -45,7 +109,125 @@ impl Handler<ServerMessage> for WsChatSession {
             }
         }
     }
-} // impl Handler<ServerMessage> for WsChatSession
+}
+
+impl WsChatSession {
+    /// Call the core from a blocking thread, since it waits on storage, then hand the result to `then`.
+    /// The session handles nothing else in the meantime, so requests are still answered in order.
+    fn with_core<T, F, C>(&mut self, ctx: &mut ws::WebsocketContext<Self>, f: F, then: C)
+    where
+        F: FnOnce(&Core) -> Result<T, String> + Send + 'static,
+        T: Send + 'static,
+        C: FnOnce(&mut Self, Result<T, String>, &mut ws::WebsocketContext<Self>) + 'static,
+    {
+        let core = self.core.clone();
+        let call = web::block(move || f(&core)).into_actor(self).map(|result, session, ctx| {
+            let result = result.map_err(|e| {
+                error!("Failed to call the core: {}", e);
+                INTERNAL_SERVER_ERROR.to_string()
+            });
+            then(session, result.and_then(|result| result), ctx)
+        });
+        ctx.wait(call);
+    }
+
+    /// A session may only send as the sender it connected as.
+    fn is_own(&self, sender: &str) -> bool {
+        sender_to_string(self.sender).as_deref() == Ok(sender)
+    }
+
+    fn send_message(&mut self, request_id: Option<String>, message: Message, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.is_own(&message.sender) {
+            return self.respond(request_id, Err(ILLEGAL_INPUT.to_string()), ctx);
+        }
+
+        let line_id = message.line_id;
+        self.with_core(ctx, move |core| core.receive_message(&message), move |session, behavior, ctx| {
+            let result = behavior.map(|behavior| {
+                match behavior {
+                    BehaviorAfterReceiveMessage::SendToAnotherSender => {
+                        debug!("Message delivered to line {}", redact(line_id));
+                    }
+                    BehaviorAfterReceiveMessage::PushedToQueue => {
+                        debug!("Message queued in line {}", redact(line_id));
+                    }
+                    BehaviorAfterReceiveMessage::NoRecipient => {
+                        debug!("Message to line {} had nobody to go to", redact(line_id));
+                    }
+                }
+                Some(behavior.into())
+            }).map_err(|e| {
+                error!("Failed to receive message: {}", e);
+                e
+            });
+            session.respond(request_id, result, ctx);
+        });
+    } // fn send_message
+
+    fn send_signal(&mut self, request_id: Option<String>, signal: Signal, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.is_own(&signal.sender) {
+            return self.respond(request_id, Err(ILLEGAL_INPUT.to_string()), ctx);
+        }
+
+        let line_id = signal.line_id;
+        self.with_core(ctx, move |core| core.relay_signal(&signal), move |session, relayed, ctx| {
+            let result = relayed.map(|relayed| {
+                if relayed {
+                    debug!("Signal relayed in line {}", redact(line_id));
+                    Some(Delivery::Live)
+                } else {
+                    debug!("Signal dropped in line {}, no peer online", redact(line_id));
+                    Some(Delivery::Dropped)
+                }
+            }).map_err(|e| {
+                error!("Failed to relay signal: {}", e);
+                e
+            });
+            session.respond(request_id, result, ctx);
+        });
+    } // fn send_signal
+
+    fn acknowledge(&mut self, request_id: Option<String>, sender: String, line_id: u16, have_up_to: u64, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.is_own(&sender) || line_id != self.line_id {
+            return self.respond(request_id, Err(ILLEGAL_INPUT.to_string()), ctx);
+        }
+        let (sender, device) = (self.sender, self.device.clone());
+        let ack = move |core: &Core| core.ack_queued(sender, &device, line_id, false, have_up_to.saturating_add(1));
+        self.with_core(ctx, ack, move |session, acked, ctx| {
+            let result = acked.map(|_| None).map_err(|e| {
+                error!("Failed to acknowledge messages: {}", e);
+                e
+            });
+            session.respond(request_id, result, ctx);
+        });
+    } // fn acknowledge
+
+    /// Tell the client how its request went. `pcp.v1` clients also get the error frame they always got.
+    fn respond(&mut self, request_id: Option<String>, result: Result<Option<Delivery>, String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let response = match result {
+            Ok(delivery) => WsResponse::success(request_id, delivery),
+            Err(e) => {
+                if self.protocol == Protocol::V1 {
+                    ctx.notify(ServerMessage::Error(e.clone()));
+                }
+                WsResponse::failure(request_id, WsErrorKind::of(&e), e)
+            }
+        };
+        ctx.notify(ServerMessage::Response(response));
+    }
+
+    fn welcome(&mut self, client: Option<String>, ctx: &mut ws::WebsocketContext<Self>) -> Result<Option<Delivery>, String> {
+        debug!("Hello from client {}", client.as_deref().unwrap_or("unknown"));
+        let limits = self.core.limits();
+        ctx.notify(ServerMessage::Welcome {
+            protocol: self.protocol.name().to_string(),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            limits,
+        });
+        Ok(None)
+    } // fn welcome
+}
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
@@ -57,48 +239,39 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                 debug!("Pong received");
             }
             Ok(ws::Message::Text(text)) => {
-                let request = match WsRequest::parse_request(&text) {
-                    Ok(request) => request,
+                let sender = match sender_to_string(self.sender) {
+                    Ok(sender) => sender,
                     Err(e) => {
-                        error!("Failed to parse request: {}", e);
+                        error!("Failed to read sender of session: {}", e);
                         return;
                     }
                 };
-                let message = request.to_message();
-                match self.core.receive_message(&message) {
-                    Ok(behavior) => {
-                        match behavior {
-                            BehaviorAfterReceiveMessage::SendToAnotherSender => {
-                                let to_sender = &message.sender;
-                                let to_sender = match crate::libs::core::string_to_sender(to_sender.to_string()) {
-                                    Ok(to_sender) => to_sender,
-                                    Err(e) => {
-                                        error!("Failed to convert string to sender: {}", e);
-                                        return;
-                                    }
-                                };
-                                // TODO
-                                match self.core.push_message_to_queue(message) {
-                                    Err(_) => {
-                                        error!("Failed to push message to queue: {}", e);
-                                        ctx.text(ServerMessage::Error("Internal server error.".to_string()));
-                                        return;
-                                    },
-                                    _ => {}
-                                };
-
-                            }
-                            BehaviorAfterReceiveMessage::PushedToQueue => {
-                                // TODO
-                            }
-                        }
-                    }
+                let (request_id, request) = match self.protocol.parse_request(&text, &sender) {
+                    Ok(parsed) => parsed,
                     Err(e) => {
-                        error!("Failed to receive message: {}", e);
+                        error!("Failed to parse request: {}", e);
+                        let response = WsResponse::failure(request_id_of(&text), WsErrorKind::InvalidRequest, e);
+                        ctx.notify(ServerMessage::Response(response));
                         return;
                     }
+                };
+                match request {
+                    ClientRequest::Hello { client } => {
+                        let result = self.welcome(client, ctx);
+                        self.respond(request_id, result, ctx);
+                    }
+                    ClientRequest::SendMessage(message) => self.send_message(request_id, message, ctx),
+                    ClientRequest::SendSignal(signal) => self.send_signal(request_id, signal, ctx),
+                    ClientRequest::Acknowledge { sender, line_id, have_up_to } => {
+                        self.acknowledge(request_id, sender, line_id, have_up_to, ctx)
+                    }
                 }
             }
+            Ok(ws::Message::Close(reason)) => {
+                info!("Session closed: {:?}", reason);
+                ctx.close(reason);
+                ctx.stop();
+            }
             _ => {}
         }
     }
@@ -0,0 +1,216 @@
+//! Protection of what is stored in Redis against whoever reads a copy of it.
+//!
+//! With a server key configured:
+//! - Key names that would contain a sender are derived with HMAC instead. Queues and bans
+//!   are named differently in every line, so a sender can't be followed from line to line.
+//!   Presence and push endpoints, which belong to no line, get one name per sender.
+//!   Members who fetched a blob are recorded by their name in the line.
+//! - Line membership, the admin of a group, the sender recorded with a queued message,
+//!   the uploader of a blob and push endpoints are encrypted with XChaCha20-Poly1305,
+//!   bound to the key they are stored under.
+//!
+//! Without a key, names and values are stored as they are. Values written before a key
+//! was configured can still be read, but keys named after senders are not migrated, so
+//! what was queued before is abandoned.
+//!
+//! Message and blob content is already encrypted by clients. Line ids, queue lengths,
+//! blob ids and sizes, and the timing of writes stay visible.
+
+use std::sync::Arc;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, aead::{Aead, AeadCore, KeyInit, OsRng, Payload}};
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Marks a value that was encrypted, anything else was stored as it is.
+const SEALED_PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 24;
+/// Hex digits of a member label, enough that two members of a line never share one.
+pub const MEMBER_LABEL_LEN: usize = 16;
+pub const MIN_KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AtRestConfig {
+    /// The server key, at least `MIN_KEY_LEN` bytes.
+    #[serde(rename = "Key", default)]
+    pub key: Option<String>,
+    /// File holding the server key instead, so it can be kept out of the config.
+    #[serde(rename = "Key File", default)]
+    pub key_file: Option<String>,
+}
+
+pub fn validate_at_rest_config(config: &AtRestConfig) -> Result<(), String> {
+    match (&config.key, &config.key_file) {
+        (Some(_), Some(_)) => Err("Only one of the at-rest key and key file may be set.".to_string()),
+        (Some(key), None) if key.len() < MIN_KEY_LEN => Err(format!("The at-rest key must be at least {} bytes.", MIN_KEY_LEN)),
+        _ => Ok(()),
+    }
+}
+
+struct Keys {
+    names: Vec<u8>,
+    values: XChaCha20Poly1305,
+}
+
+/// Shared by every store that writes to Redis. Does nothing when no key is configured.
+#[derive(Clone, Default)]
+pub struct AtRest {
+    keys: Option<Arc<Keys>>,
+}
+
+fn derive(key: &[u8], purpose: &str) -> Vec<u8> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(purpose.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl AtRest {
+    pub fn load(config: &AtRestConfig) -> Result<Self, String> {
+        validate_at_rest_config(config)?;
+        match (&config.key, &config.key_file) {
+            (Some(key), _) => Self::with_key(key.as_bytes()),
+            (None, Some(path)) => {
+                let key = std::fs::read(path).map_err(|e| e.to_string())?;
+                Self::with_key(key.trim_ascii_end())
+            }
+            (None, None) => Ok(AtRest::default()),
+        }
+    }
+
+    fn with_key(key: &[u8]) -> Result<Self, String> {
+        if key.len() < MIN_KEY_LEN {
+            return Err(format!("The at-rest key must be at least {} bytes.", MIN_KEY_LEN));
+        }
+        let values = XChaCha20Poly1305::new_from_slice(&derive(key, "at-rest values")).map_err(|e| e.to_string())?;
+        Ok(AtRest {
+            keys: Some(Arc::new(Keys { names: derive(key, "at-rest names"), values })),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    fn name(&self, input: String) -> Option<String> {
+        let keys = self.keys.as_ref()?;
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&keys.names).expect("HMAC accepts any key length");
+        mac.update(input.as_bytes());
+        Some(to_hex(&mac.finalize().into_bytes()))
+    }
+
+    /// Stands for `sender` in the names of keys that belong to no line.
+    pub fn sender_name(&self, sender: &str) -> String {
+        self.name(format!("sender:{}", sender)).unwrap_or_else(|| sender.to_string())
+    }
+
+    /// Stands for `sender` in the names of the keys of a line.
+    pub fn member_name(&self, line_id: u16, sender: &str) -> String {
+        self.name(format!("member:{}:{}", line_id, sender)).unwrap_or_else(|| sender.to_string())
+    }
+
+    /// What a member is called wherever others may see it, in place of its identifier,
+    /// which is its credential. The same in a line and different in every other one.
+    /// Without a key it is a plain hash, as hard to reverse as the identifier is to guess.
+    pub fn member_label(&self, line_id: u16, sender: &str) -> String {
+        let input = format!("label:{}:{}", line_id, sender);
+        let mut label = self.name(input.clone()).unwrap_or_else(|| to_hex(&Sha256::digest(input.as_bytes())));
+        label.truncate(MEMBER_LABEL_LEN);
+        label
+    }
+
+    /// Encrypt a value stored under `key`, where it alone can be opened.
+    pub fn seal(&self, key: &str, value: &str) -> Result<String, String> {
+        let keys = match &self.keys {
+            Some(keys) => keys,
+            None => return Ok(value.to_string()),
+        };
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = keys.values.encrypt(&nonce, Payload { msg: value.as_bytes(), aad: key.as_bytes() })
+            .map_err(|e| e.to_string())?;
+        Ok(format!("{}{}{}", SEALED_PREFIX, to_hex(&nonce), to_hex(&ciphertext)))
+    }
+
+    pub fn open(&self, key: &str, stored: &str) -> Result<String, String> {
+        let sealed = match stored.strip_prefix(SEALED_PREFIX) {
+            Some(sealed) => from_hex(sealed).filter(|sealed| sealed.len() >= NONCE_LEN).ok_or("Malformed encrypted value.")?,
+            None => return Ok(stored.to_string()),
+        };
+        let keys = self.keys.as_ref().ok_or("Encrypted value found, but no at-rest key is configured.")?;
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plain = keys.values.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: key.as_bytes() })
+            .map_err(|_| "Failed to decrypt a value, was the at-rest key changed?".to_string())?;
+        String::from_utf8(plain).map_err(|e| e.to_string())
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn enabled() -> AtRest {
+        AtRest::load(&AtRestConfig { key: Some(KEY.to_string()), key_file: None }).unwrap()
+    }
+
+    #[test]
+    fn disabled_stores_everything_as_is() {
+        let at_rest = AtRest::default();
+        assert_eq!(at_rest.member_name(1, "alice"), "alice");
+        assert_eq!(at_rest.seal("sender:1:line", "alice:bob").unwrap(), "alice:bob");
+        assert_eq!(at_rest.open("sender:1:line", "alice:bob").unwrap(), "alice:bob");
+    }
+
+    #[test]
+    fn names_differ_per_line() {
+        let at_rest = enabled();
+        let name = at_rest.member_name(1, "alice");
+        assert_ne!(name, "alice");
+        assert_eq!(name, at_rest.member_name(1, "alice"));
+        assert_ne!(name, at_rest.member_name(2, "alice"));
+        assert_ne!(at_rest.sender_name("alice"), name);
+    }
+
+    #[test]
+    fn labels_differ_per_line_and_hide_the_sender() {
+        for at_rest in [AtRest::default(), enabled()] {
+            let label = at_rest.member_label(1, "alice");
+            assert_eq!(label.len(), MEMBER_LABEL_LEN);
+            assert!(!label.contains("alice"));
+            assert_eq!(label, at_rest.member_label(1, "alice"));
+            assert_ne!(label, at_rest.member_label(2, "alice"));
+        }
+        assert_ne!(AtRest::default().member_label(1, "alice"), enabled().member_label(1, "alice"));
+    }
+
+    #[test]
+    fn values_only_open_under_their_key() {
+        let at_rest = enabled();
+        let sealed = at_rest.seal("sender:1:line", "alice:bob").unwrap();
+        assert!(!sealed.contains("alice"));
+        assert_eq!(at_rest.open("sender:1:line", &sealed).unwrap(), "alice:bob");
+        assert!(at_rest.open("sender:2:line", &sealed).is_err());
+        assert!(AtRest::default().open("sender:1:line", &sealed).is_err());
+        // Written before the key was configured.
+        assert_eq!(at_rest.open("sender:1:line", "alice:bob").unwrap(), "alice:bob");
+    }
+
+    #[test]
+    fn short_or_duplicate_keys_are_rejected() {
+        assert!(AtRest::load(&AtRestConfig { key: Some("short".to_string()), key_file: None }).is_err());
+        assert!(validate_at_rest_config(&AtRestConfig { key: Some(KEY.to_string()), key_file: Some("key".to_string()) }).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use crate::libs::message::retention::KeyExpiry;
+use super::store_trait::BlobStoreBackend;
+
+/// Keeps every blob in a file of its own.
+pub struct FsBlobStore {
+    directory: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(directory: &str) -> Result<Self, String> {
+        fs::create_dir_all(directory).map_err(|e| e.to_string())?;
+        Ok(FsBlobStore { directory: PathBuf::from(directory) })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.directory.join(name)
+    }
+}
+
+impl BlobStoreBackend for FsBlobStore {
+    fn append(&self, name: &str, chunk: &[u8], _expiry: KeyExpiry) -> Result<u64, String> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(name))
+            .map_err(|e| e.to_string())?;
+        file.write_all(chunk).map_err(|e| e.to_string())?;
+        let metadata = file.metadata().map_err(|e| e.to_string())?;
+        Ok(metadata.len())
+    }
+
+    fn read_range(&self, name: &str, offset: u64, len: u64) -> Result<Vec<u8>, String> {
+        let mut file = match File::open(self.path(name)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.to_string()),
+        };
+        file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+        let mut data = Vec::new();
+        file.take(len).read_to_end(&mut data).map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    /// A reader never sees a partial blob, a rename within a directory is atomic.
+    fn rename(&self, from: &str, to: &str, _expiry: KeyExpiry) -> Result<(), String> {
+        fs::rename(self.path(from), self.path(to)).map_err(|e| e.to_string())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), String> {
+        match fs::remove_file(self.path(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.directory).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use redis::Commands;
+use crate::libs::message::keys::{blob_key, blob_fetched_key, upload_key};
+use crate::libs::message::retention::key_expiry;
+use crate::libs::metrics::{observe_storage, REDIS_BACKEND};
+use crate::libs::redis_connect::{RedisConnection, RedisPool, PooledConnection, AutoDeleteTime, apply_key_expiry};
+use crate::libs::at_rest::AtRest;
+use super::UPLOAD_TTL;
+
+/// A blob that is still being uploaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upload {
+    pub line_id: u16,
+    pub uploader: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobMeta {
+    pub uploader: String,
+    pub size: u64,
+}
+
+/// Metadata of blobs and uploads, kept in Redis whatever stores the content.
+/// Uploaders are encrypted and members who fetched a blob are named like in keys, see `at_rest`.
+pub struct BlobIndex {
+    client: RedisPool,
+    auto_delete_time: AutoDeleteTime,
+    at_rest: AtRest,
+}
+
+impl BlobIndex {
+    pub fn new(config: &RedisConnection) -> Self {
+        BlobIndex {
+            client: config.get_client(),
+            auto_delete_time: config.auto_delete_time.clone(),
+            at_rest: config.at_rest.clone(),
+        }
+    }
+
+    fn connection(&self) -> Result<PooledConnection, String> {
+        self.client.get_connection().map_err(|e| e.to_string())
+    }
+
+    pub fn create_upload(&self, upload_id: &str, line_id: u16, uploader: &str) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "create_upload", || {
+            let uploader = self.at_rest.seal(&upload_key(upload_id), uploader)?;
+            let mut con = self.connection()?;
+            redis::pipe()
+                .hset_multiple(upload_key(upload_id), &[
+                    ("line_id", line_id.to_string()),
+                    ("uploader", uploader),
+                    ("size", "0".to_string()),
+                ]).ignore()
+                .expire(upload_key(upload_id), UPLOAD_TTL.as_secs() as usize).ignore()
+                .query(&mut con)
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    pub fn upload(&self, upload_id: &str) -> Result<Option<Upload>, String> {
+        observe_storage(REDIS_BACKEND, "get_upload", || {
+            let mut con = self.connection()?;
+            let (line_id, uploader, size): (Option<u16>, Option<String>, Option<u64>) = redis::cmd("HMGET")
+                .arg(upload_key(upload_id))
+                .arg("line_id")
+                .arg("uploader")
+                .arg("size")
+                .query(&mut con)
+                .map_err(|e| e.to_string())?;
+            match (line_id, uploader, size) {
+                (Some(line_id), Some(uploader), Some(size)) => {
+                    let uploader = self.at_rest.open(&upload_key(upload_id), &uploader)?;
+                    Ok(Some(Upload { line_id, uploader, size }))
+                }
+                _ => Ok(None),
+            }
+        })
+    }
+
+    pub fn set_upload_size(&self, upload_id: &str, size: u64) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "set_upload_size", || {
+            let mut con = self.connection()?;
+            con.hset(upload_key(upload_id), "size", size).map_err(|e| e.to_string())
+        })
+    }
+
+    pub fn remove_upload(&self, upload_id: &str) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "remove_upload", || {
+            let mut con = self.connection()?;
+            con.del(upload_key(upload_id)).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Record a finished blob. Uploading the same content again starts its retention anew.
+    pub fn add_blob(&self, line_id: u16, blob_id: &str, meta: &BlobMeta) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "add_blob", || {
+            let key = blob_key(line_id, blob_id);
+            let uploader = self.at_rest.seal(&key, &meta.uploader)?;
+            let mut con = self.connection()?;
+            redis::pipe()
+                .del(blob_fetched_key(line_id, blob_id)).ignore()
+                .hset_multiple(&key, &[
+                    ("uploader", uploader),
+                    ("size", meta.size.to_string()),
+                ]).ignore()
+                .query::<()>(&mut con)
+                .map_err(|e| e.to_string())?;
+            apply_key_expiry(&mut con, &key, key_expiry(self.auto_delete_time.get()))
+        })
+    }
+
+    pub fn blob(&self, line_id: u16, blob_id: &str) -> Result<Option<BlobMeta>, String> {
+        observe_storage(REDIS_BACKEND, "get_blob", || {
+            let mut con = self.connection()?;
+            let (uploader, size): (Option<String>, Option<u64>) = redis::cmd("HMGET")
+                .arg(blob_key(line_id, blob_id))
+                .arg("uploader")
+                .arg("size")
+                .query(&mut con)
+                .map_err(|e| e.to_string())?;
+            match (uploader, size) {
+                (Some(uploader), Some(size)) => {
+                    let uploader = self.at_rest.open(&blob_key(line_id, blob_id), &uploader)?;
+                    Ok(Some(BlobMeta { uploader, size }))
+                }
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// Record that `sender` fetched the blob. Returns whether every one of `members` has by now.
+    pub fn mark_fetched(&self, line_id: u16, blob_id: &str, sender: &str, members: &[&String]) -> Result<bool, String> {
+        observe_storage(REDIS_BACKEND, "mark_blob_fetched", || {
+            let mut con = self.connection()?;
+            let key = blob_fetched_key(line_id, blob_id);
+            con.sadd::<_, _, ()>(&key, self.at_rest.member_name(line_id, sender)).map_err(|e| e.to_string())?;
+            // Lives exactly as long as the blob itself.
+            let ttl: i64 = con.ttl(blob_key(line_id, blob_id)).map_err(|e| e.to_string())?;
+            if ttl > 0 {
+                con.expire::<_, ()>(&key, ttl as usize).map_err(|e| e.to_string())?;
+            }
+            let fetched: HashSet<String> = con.smembers(&key).map_err(|e| e.to_string())?;
+            Ok(members.iter().all(|member| fetched.contains(&self.at_rest.member_name(line_id, member))))
+        })
+    }
+
+    pub fn remove_blob(&self, line_id: u16, blob_id: &str) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "remove_blob", || {
+            let mut con = self.connection()?;
+            con.del(&[blob_key(line_id, blob_id), blob_fetched_key(line_id, blob_id)]).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Whether the metadata of the content named in the store still exists, for each of `names`.
+    pub fn are_live(&self, names: &[String]) -> Result<Vec<bool>, String> {
+        let keys: Vec<Option<String>> = names.iter().map(|name| match name.strip_prefix("upload-") {
+            Some(upload_id) => Some(upload_key(upload_id)),
+            // Not ours, or a write that never finished.
+            None => name.split_once('-')
+                .and_then(|(line_id, blob_id)| Some(blob_key(line_id.parse().ok()?, blob_id))),
+        }).collect();
+        if keys.iter().all(Option::is_none) {
+            return Ok(vec![false; names.len()]);
+        }
+        let mut pipe = redis::pipe();
+        for key in keys.iter().flatten() {
+            pipe.exists(key);
+        }
+        let mut exists: std::vec::IntoIter<bool> = observe_storage(REDIS_BACKEND, "blobs_are_live", || {
+            let mut con = self.connection()?;
+            pipe.query::<Vec<bool>>(&mut con).map_err(|e| e.to_string())
+        })?.into_iter();
+        Ok(keys.iter().map(|key| key.is_some() && exists.next().unwrap_or(false)).collect())
+    }
+}
@@ -0,0 +1,171 @@
+//! Attachments, uploaded by clients as encrypted blobs.
+//!
+//! A blob is uploaded to a line in chunks, then named after the SHA-256 of its content.
+//! Clients refer to a blob by that id inside the encrypted content of a message, so the
+//! server knows neither what a blob contains nor which messages refer to it.
+//!
+//! A blob is deleted once every other member of its line has fetched it. Otherwise it
+//! expires like a queue, see `retention`. Unfinished uploads expire after `UPLOAD_TTL`.
+//!
+//! Metadata always lives in Redis, see `index`. Content lives in the configured store.
+
+pub mod store_trait;
+pub mod fs_store;
+pub mod redis_store;
+pub mod index;
+
+use std::time::Duration;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::libs::at_rest::to_hex;
+use crate::libs::message::retention::KeyExpiry;
+use crate::libs::metrics::{observe_storage, FILESYSTEM_BACKEND, REDIS_BACKEND};
+use fs_store::FsBlobStore;
+use redis_store::RedisBlobStore;
+use store_trait::BlobStoreBackend;
+
+pub const FILESYSTEM_STORAGE: &str = "filesystem";
+pub const REDIS_STORAGE: &str = "redis";
+
+/// How long an upload may take from start to completion.
+pub const UPLOAD_TTL: Duration = Duration::from_secs(3_600);
+
+/// Content is read in parts this large, so a blob is never held in memory whole.
+pub const READ_CHUNK_SIZE: u64 = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlobConfig {
+    /// `redis` or `filesystem`.
+    #[serde(rename = "Storage", default = "default_storage")]
+    pub storage: String,
+    /// Where the filesystem storage keeps blobs.
+    #[serde(rename = "Directory", default = "default_directory")]
+    pub directory: String,
+    /// Largest accepted blob, in bytes.
+    #[serde(rename = "Max Blob Size", default = "default_max_blob_size")]
+    pub max_blob_size: u64,
+}
+
+fn default_storage() -> String {
+    REDIS_STORAGE.to_string()
+}
+
+fn default_directory() -> String {
+    "./blobs".to_string()
+}
+
+fn default_max_blob_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        BlobConfig {
+            storage: default_storage(),
+            directory: default_directory(),
+            max_blob_size: default_max_blob_size(),
+        }
+    }
+}
+
+pub enum BlobStore {
+    Filesystem(FsBlobStore),
+    Redis(RedisBlobStore),
+}
+
+impl BlobStore {
+    /// Name of the backend, used to label metrics.
+    pub fn backend(&self) -> &'static str {
+        match self {
+            BlobStore::Filesystem(_) => FILESYSTEM_BACKEND,
+            BlobStore::Redis(_) => REDIS_BACKEND,
+        }
+    }
+
+    pub fn append(&self, name: &str, chunk: &[u8], expiry: KeyExpiry) -> Result<u64, String> {
+        observe_storage(self.backend(), "blob_append", || match self {
+            BlobStore::Filesystem(s) => s.append(name, chunk, expiry),
+            BlobStore::Redis(s) => s.append(name, chunk, expiry),
+        })
+    }
+
+    pub fn read_range(&self, name: &str, offset: u64, len: u64) -> Result<Vec<u8>, String> {
+        observe_storage(self.backend(), "blob_read", || match self {
+            BlobStore::Filesystem(s) => s.read_range(name, offset, len),
+            BlobStore::Redis(s) => s.read_range(name, offset, len),
+        })
+    }
+
+    pub fn rename(&self, from: &str, to: &str, expiry: KeyExpiry) -> Result<(), String> {
+        observe_storage(self.backend(), "blob_rename", || match self {
+            BlobStore::Filesystem(s) => s.rename(from, to, expiry),
+            BlobStore::Redis(s) => s.rename(from, to, expiry),
+        })
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), String> {
+        observe_storage(self.backend(), "blob_delete", || match self {
+            BlobStore::Filesystem(s) => s.delete(name),
+            BlobStore::Redis(s) => s.delete(name),
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<String>, String> {
+        observe_storage(self.backend(), "blob_list", || match self {
+            BlobStore::Filesystem(s) => s.list(),
+            BlobStore::Redis(s) => s.list(),
+        })
+    }
+}
+
+/// Name of the content of a finished blob in the store.
+pub fn blob_name(line_id: u16, blob_id: &str) -> String {
+    format!("{}-{}", line_id, blob_id)
+}
+
+/// Name of the content of an unfinished upload in the store.
+pub fn upload_name(upload_id: &str) -> String {
+    format!("upload-{}", upload_id)
+}
+
+/// Hex encoded SHA-256 of the content, read from the store in parts.
+pub fn blob_id(store: &BlobStore, name: &str, size: u64) -> Result<String, String> {
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    while offset < size {
+        let chunk = store.read_range(name, offset, READ_CHUNK_SIZE.min(size - offset))?;
+        if chunk.is_empty() {
+            return Err(format!("Content of {} ended after {} bytes.", name, offset));
+        }
+        hasher.update(&chunk);
+        offset += chunk.len() as u64;
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Ids are lowercase hex, which also keeps them safe to use as file names.
+pub fn is_valid_id(id: &str, len: usize) -> bool {
+    id.len() == len && id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+pub const BLOB_ID_LEN: usize = 64;
+pub const UPLOAD_ID_LEN: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blob_ids_are_sha256() {
+        let directory = std::env::temp_dir().join(format!("blob-ids-{}", std::process::id()));
+        let store = BlobStore::Filesystem(FsBlobStore::new(directory.to_str().unwrap()).unwrap());
+        store.append("abc", b"abc", KeyExpiry::Persist).unwrap();
+        let id = blob_id(&store, "abc", 3).unwrap();
+        assert!(blob_id(&store, "abc", 4).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(id, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert!(is_valid_id(&id, BLOB_ID_LEN));
+        assert!(!is_valid_id(&id.to_uppercase(), BLOB_ID_LEN));
+        assert!(!is_valid_id("../../etc/passwd", BLOB_ID_LEN));
+    }
+}
@@ -0,0 +1,55 @@
+use redis::Commands;
+use crate::libs::message::keys::blob_content_key;
+use crate::libs::message::retention::KeyExpiry;
+use crate::libs::redis_connect::{RedisConnection, RedisPool, PooledConnection, apply_key_expiry};
+use super::store_trait::BlobStoreBackend;
+
+/// Keeps every blob in a string key, which expires on its own.
+pub struct RedisBlobStore {
+    client: RedisPool,
+}
+
+impl RedisBlobStore {
+    pub fn new(config: &RedisConnection) -> Self {
+        RedisBlobStore { client: config.get_client() }
+    }
+
+    fn connection(&self) -> Result<PooledConnection, String> {
+        self.client.get_connection().map_err(|e| e.to_string())
+    }
+}
+
+impl BlobStoreBackend for RedisBlobStore {
+    fn append(&self, name: &str, chunk: &[u8], expiry: KeyExpiry) -> Result<u64, String> {
+        let mut con = self.connection()?;
+        let key = blob_content_key(name);
+        let size: u64 = con.append(&key, chunk).map_err(|e| e.to_string())?;
+        apply_key_expiry(&mut con, &key, expiry)?;
+        Ok(size)
+    }
+
+    fn read_range(&self, name: &str, offset: u64, len: u64) -> Result<Vec<u8>, String> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let mut con = self.connection()?;
+        con.getrange(blob_content_key(name), offset as isize, (offset + len - 1) as isize).map_err(|e| e.to_string())
+    }
+
+    fn rename(&self, from: &str, to: &str, expiry: KeyExpiry) -> Result<(), String> {
+        let mut con = self.connection()?;
+        let key = blob_content_key(to);
+        con.rename::<_, _, ()>(blob_content_key(from), &key).map_err(|e| e.to_string())?;
+        apply_key_expiry(&mut con, &key, expiry)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), String> {
+        let mut con = self.connection()?;
+        con.del(blob_content_key(name)).map_err(|e| e.to_string())
+    }
+
+    /// Content expires together with its metadata, nothing has to be swept.
+    fn list(&self) -> Result<Vec<String>, String> {
+        Ok(Vec::new())
+    }
+}
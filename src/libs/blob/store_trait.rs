@@ -0,0 +1,16 @@
+use crate::libs::message::retention::KeyExpiry;
+
+/// Where the content of blobs is kept, by name.
+/// `expiry` only applies to backends that expire content on their own,
+/// the content of other backends is removed by `Core::sweep_blobs`.
+pub trait BlobStoreBackend {
+    /// Append to the content, creating it if needed. Returns its new size.
+    fn append(&self, name: &str, chunk: &[u8], expiry: KeyExpiry) -> Result<u64, String>;
+    /// Up to `len` bytes of the content from `offset`, empty past its end or if there is none.
+    fn read_range(&self, name: &str, offset: u64, len: u64) -> Result<Vec<u8>, String>;
+    /// Move the content to `to`, replacing what was there.
+    fn rename(&self, from: &str, to: &str, expiry: KeyExpiry) -> Result<(), String>;
+    fn delete(&self, name: &str) -> Result<(), String>;
+    /// Names of the content that has to be swept once its metadata is gone.
+    fn list(&self) -> Result<Vec<String>, String>;
+}
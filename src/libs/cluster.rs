@@ -0,0 +1,317 @@
+//! Running several instances against one Redis.
+//!
+//! Every instance records the senders it holds a session of in `presence:{sender}`,
+//! a sorted set of instance ids scored by the time their lease runs out. Leases are
+//! renewed while the session lives, so the presence of a crashed instance expires on its own.
+//!
+//! A live message for a sender who is not online on this instance is published on
+//! `deliver:{sender}`. Only the instance holding the session subscribes to it, and it
+//! queues the message when the session went away in the meantime. Every event carries
+//! the instance that published it, which skips its own, its sessions already got them.
+//!
+//! With at-rest protection, both are named after the pseudonym of the sender instead,
+//! see `at_rest`.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use redis::{Commands, ConnectionLike, Msg};
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, error, debug};
+
+use super::core::SharedCore;
+use super::message::Message;
+use super::message::keys::{presence_key, delivery_channel, sender_from_channel};
+use super::message::retention::now_seconds;
+use super::metrics::{observe_storage, REDIS_BACKEND};
+use super::redis_connect::{RedisConnection, RedisPool};
+use super::at_rest::AtRest;
+use super::ws::ws_sent_message::{DisconnectReason, ServerMessage};
+
+/// How long the listener waits for a message before handling subscriptions and leases.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    #[serde(rename = "Enabled", default)]
+    pub enabled: bool,
+    /// How long the presence of a session outlives an instance that stopped renewing it.
+    #[serde(rename = "Session Lease", default = "default_session_lease")]
+    pub session_lease: String,
+}
+
+fn default_session_lease() -> String {
+    "30s".to_string()
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            enabled: false,
+            session_lease: default_session_lease(),
+        }
+    }
+}
+
+/// What one instance asks the instance holding a session to do.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClusterEvent {
+    Deliver(Message),
+    /// A server event for the sessions of the sender, never queued.
+    Notify(ServerMessage),
+    Disconnect(DisconnectReason),
+    /// The sender left a line, its sessions in it are closed.
+    Leave(u16),
+}
+
+/// What is published: the event and the instance it came from.
+#[derive(Debug, Serialize, Deserialize)]
+struct Published<E> {
+    origin: String,
+    event: E,
+}
+
+enum Subscription {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+/// Presence and delivery to sessions on other instances.
+pub struct Cluster {
+    client: RedisPool,
+    at_rest: AtRest,
+    instance_id: String,
+    lease: u64,
+    subscriptions: mpsc::Sender<Subscription>,
+    /// The senders holding a session on this instance, which hears what is published to them too.
+    joined: Mutex<HashSet<String>>,
+}
+
+/// Receives the messages published to the senders online on this instance.
+pub struct ClusterListener {
+    client: RedisPool,
+    at_rest: AtRest,
+    instance_id: String,
+    lease: u64,
+    subscriptions: mpsc::Receiver<Subscription>,
+}
+
+/// Unique for every run of the server.
+fn new_instance_id() -> String {
+    let seed = RandomState::new().hash_one(SystemTime::now());
+    format!("{:016x}-{}", seed, std::process::id())
+}
+
+impl Cluster {
+    pub fn new(connection: &RedisConnection, lease: u64) -> (Cluster, ClusterListener) {
+        let instance_id = new_instance_id();
+        let (sender, receiver) = mpsc::channel();
+        info!("Cluster mode enabled, instance {}", instance_id);
+        let cluster = Cluster {
+            client: connection.get_client(),
+            at_rest: connection.at_rest.clone(),
+            instance_id: instance_id.clone(),
+            lease,
+            subscriptions: sender,
+            joined: Mutex::new(HashSet::new()),
+        };
+        let listener = ClusterListener {
+            client: connection.get_client(),
+            at_rest: connection.at_rest.clone(),
+            instance_id,
+            lease,
+            subscriptions: receiver,
+        };
+        (cluster, listener)
+    }
+
+    /// Announce a session of `sender` on this instance.
+    pub fn join(&self, sender: &str) -> Result<(), String> {
+        self.joined.lock().unwrap().insert(sender.to_string());
+        let _ = self.subscriptions.send(Subscription::Subscribe(sender.to_string()));
+        observe_storage(REDIS_BACKEND, "cluster_join", || {
+            let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+            renew_presence(&mut con, &self.instance_id, self.lease, [self.at_rest.sender_name(sender).as_str()])
+        })
+    }
+
+    /// Withdraw the session of `sender` on this instance.
+    pub fn leave(&self, sender: &str) -> Result<(), String> {
+        self.joined.lock().unwrap().remove(sender);
+        let _ = self.subscriptions.send(Subscription::Unsubscribe(sender.to_string()));
+        observe_storage(REDIS_BACKEND, "cluster_leave", || {
+            let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+            con.zrem(presence_key(&self.at_rest.sender_name(sender)), &self.instance_id).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Whether any instance holds a session of `sender`.
+    pub fn is_online(&self, sender: &str) -> Result<bool, String> {
+        observe_storage(REDIS_BACKEND, "cluster_is_online", || {
+            let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+            let leases: u64 = con.zcount(presence_key(&self.at_rest.sender_name(sender)), format!("({}", now_seconds()), "+inf")
+                .map_err(|e| e.to_string())?;
+            Ok(leases > 0)
+        })
+    }
+
+    /// Send an event to the instances holding a session of `sender`.
+    /// Returns whether any instance other than this one received it.
+    pub fn publish(&self, sender: &str, event: &ClusterEvent) -> Result<bool, String> {
+        let payload = serde_json::to_string(&Published { origin: self.instance_id.clone(), event }).map_err(|e| e.to_string())?;
+        let joined_here = self.joined.lock().unwrap().contains(sender);
+        observe_storage(REDIS_BACKEND, "cluster_publish", || {
+            let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+            let receivers: u64 = con.publish(delivery_channel(&self.at_rest.sender_name(sender)), payload).map_err(|e| e.to_string())?;
+            Ok(reached_others(receivers, joined_here))
+        })
+    }
+} // impl Cluster
+
+/// This instance is among the `receivers` of what is published to a sender it holds a session of.
+fn reached_others(receivers: u64, joined_here: bool) -> bool {
+    receivers > u64::from(joined_here)
+}
+
+/// `names` are what the senders are known as in key names, see `AtRest::sender_name`.
+fn renew_presence<'a>(con: &mut impl ConnectionLike, instance_id: &str, lease: u64, names: impl IntoIterator<Item = &'a str>) -> Result<(), String> {
+    let lease_until = now_seconds().saturating_add(lease);
+    let mut pipe = redis::pipe();
+    for name in names {
+        let key = presence_key(name);
+        pipe.zadd(&key, instance_id, lease_until).ignore()
+            .expire(&key, lease as usize).ignore();
+    }
+    pipe.query(con).map_err(|e| e.to_string())
+}
+
+impl ClusterListener {
+    /// Deliver published events to `core` until the `Cluster` is dropped.
+    pub fn spawn(self, core: SharedCore) {
+        let spawned = thread::Builder::new()
+            .name("cluster-listener".to_string())
+            .spawn(move || {
+                // Kept across reconnects, so the subscriptions can be restored.
+                // Maps the name each sender is known as in Redis back to the sender.
+                let mut senders = HashMap::new();
+                while let Err(e) = self.listen(&core, &mut senders) {
+                    error!("Cluster listener failed, reconnecting: {}", e);
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Failed to start the cluster listener: {}", e);
+        }
+    }
+
+    /// Returns `Ok` once the `Cluster` is gone.
+    fn listen(&self, core: &SharedCore, senders: &mut HashMap<String, String>) -> Result<(), String> {
+        let client = self.client.client();
+        let mut con = client.get_connection().map_err(|e| e.to_string())?;
+        let mut lease_con = client.get_connection().map_err(|e| e.to_string())?;
+        con.set_read_timeout(Some(POLL_INTERVAL)).map_err(|e| e.to_string())?;
+
+        // Subscriptions are sent without waiting for their replies, since a message
+        // published in between would be taken for the reply and lost.
+        for name in senders.keys() {
+            send_subscription(&mut con, "SUBSCRIBE", name)?;
+        }
+        if !senders.is_empty() {
+            renew_presence(&mut lease_con, &self.instance_id, self.lease, senders.keys().map(String::as_str))?;
+        }
+        let mut last_renewal = Instant::now();
+        let renewal_interval = Duration::from_secs(self.lease / 3).max(POLL_INTERVAL);
+
+        loop {
+            loop {
+                match self.subscriptions.try_recv() {
+                    Ok(Subscription::Subscribe(sender)) => {
+                        let name = self.at_rest.sender_name(&sender);
+                        send_subscription(&mut con, "SUBSCRIBE", &name)?;
+                        senders.insert(name, sender);
+                    }
+                    Ok(Subscription::Unsubscribe(sender)) => {
+                        let name = self.at_rest.sender_name(&sender);
+                        senders.remove(&name);
+                        send_subscription(&mut con, "UNSUBSCRIBE", &name)?;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+
+            if last_renewal.elapsed() >= renewal_interval && !senders.is_empty() {
+                renew_presence(&mut lease_con, &self.instance_id, self.lease, senders.keys().map(String::as_str))?;
+                last_renewal = Instant::now();
+            }
+
+            match con.recv_response() {
+                // Replies to subscriptions are not messages and are skipped.
+                Ok(value) => {
+                    if let Some(msg) = Msg::from_value(&value) {
+                        self.dispatch(core, senders, msg);
+                    }
+                }
+                Err(e) if e.is_timeout() => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    fn dispatch(&self, core: &SharedCore, senders: &HashMap<String, String>, msg: Msg) {
+        let sender = match sender_from_channel(msg.get_channel_name()).and_then(|name| senders.get(name)) {
+            Some(sender) => sender.clone(),
+            None => return,
+        };
+        let event = msg.get_payload::<String>()
+            .map_err(|e| e.to_string())
+            .and_then(|payload| decode(&payload, &self.instance_id));
+        match event {
+            Ok(Some(event)) => core.handle_cluster_event(sender, event),
+            Ok(None) => {}
+            Err(e) => debug!("Ignoring malformed cluster event: {}", e),
+        }
+    }
+} // impl ClusterListener
+
+/// `None` for the events the instance `instance_id` published itself.
+fn decode(payload: &str, instance_id: &str) -> Result<Option<ClusterEvent>, String> {
+    let published: Published<ClusterEvent> = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    Ok(Some(published.event).filter(|_| published.origin != instance_id))
+}
+
+fn send_subscription(con: &mut redis::Connection, command: &str, name: &str) -> Result<(), String> {
+    let packed = redis::cmd(command).arg(delivery_channel(name)).get_packed_command();
+    con.send_packed_command(&packed).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(origin: &str) -> String {
+        let event = ClusterEvent::Disconnect(DisconnectReason::Kicked);
+        serde_json::to_string(&Published { origin: origin.to_string(), event: &event }).unwrap()
+    }
+
+    #[test]
+    fn only_other_instances_dispatch_an_event() {
+        let payload = publish("a");
+        let dispatched = ["a", "b"].iter()
+            .filter(|id| decode(&payload, id).unwrap().is_some())
+            .count();
+        assert_eq!(dispatched, 1);
+    }
+
+    #[test]
+    fn publishing_to_a_sender_held_here_reaches_others_only_beyond_this_instance() {
+        assert!(!reached_others(1, true));
+        assert!(reached_others(2, true));
+        assert!(reached_others(1, false));
+        assert!(!reached_others(0, false));
+    }
+}
@@ -1,22 +1,82 @@
-use std::collections::HashSet;
-use tracing::{info, error, debug};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use actix_web::{rt, web};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use actix::Recipient;
+use serde_derive::Serialize;
+use tracing::{info, warn, error, debug};
 
 use crate::libs::message::Message;
-use super::message::queue_trait::MessageQueueStore;
+use crate::libs::message::signal::{Signal, SignalKind};
+use super::message::queue_trait::QueuedMessages;
+use super::ws::ws_sent_message::{DisconnectReason, Limits, ServerMessage};
 use super::load_config::{Queue, LoadResult};
-use super::message::line_manage::{LineManager, AddSenderActuallyDone};
-use super::parse_config::Profile;
+use super::message::line_manage::{LineManager, AddSenderActuallyDone, Group, LINE_MEMBERS};
+use super::live_config::LiveConfig;
+use super::metrics::metrics;
+use super::logging::redact;
+use super::cluster::{Cluster, ClusterEvent};
+use super::notifier::{PushEndpoints, WakeUps};
+use super::blob::{self, BlobStore, UPLOAD_TTL, blob_name, upload_name, index::{BlobIndex, BlobMeta}};
+use super::message::retention::{key_expiry, KeyExpiry};
+use super::message::keys::sealed_queue_owner;
+use super::sealed;
+use super::at_rest::{MEMBER_LABEL_LEN, to_hex};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
 
 pub type Sender = [u8; 64];
 
-const INTERNAL_SERVER_ERROR: &str = "Internal server error.";
-const TRY_TO_JOIN_BUSY_LINE: &str = "Try to join busy line.";
-const SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN: &str = "Sending to the line that you are not in";
-const ILLEGAL_INPUT: &str = "Illegal input.";
+/// `Core` is shared by every session and HTTP handler. It locks the state it keeps in memory
+/// on its own and only briefly, never while waiting on storage. Calls that reach storage block,
+/// so they are made from blocking threads, never from the async workers.
+pub type SharedCore = Arc<Core>;
+
+pub const INTERNAL_SERVER_ERROR: &str = "Internal server error.";
+pub const TRY_TO_JOIN_BUSY_LINE: &str = "Try to join busy line.";
+pub const SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN: &str = "Sending to the line that you are not in";
+pub const ILLEGAL_INPUT: &str = "Illegal input.";
+pub const MESSAGE_TOO_LARGE: &str = "Message is too large.";
+pub const REGISTRATION_CLOSED: &str = "Registration is closed, only existing lines can be joined.";
+pub const EVICTED_BY_ADMIN: &str = "Evicted from the line by the server admin.";
+pub const SERVER_RESTARTING: &str = "Server is restarting, please reconnect later.";
+pub const KICKED_FROM_LINE: &str = "Removed from the group by its admin.";
+pub const LEFT_LINE: &str = "Left the line.";
+pub const NOT_GROUP_ADMIN: &str = "Only the admin of a group may do this.";
+pub const NOT_A_MEMBER: &str = "Not a member of the line.";
+pub const INVALID_GROUP_SIZE: &str = "Group size is not allowed.";
+pub const PEER_OFFLINE: &str = "The peer is offline.";
+pub const CALLS_NEED_TWO_MEMBERS: &str = "Calls are only possible in lines of two members.";
+pub const BLOB_NOT_FOUND: &str = "Blob not found.";
+pub const UPLOAD_NOT_FOUND: &str = "Upload not found.";
+pub const BLOB_TOO_LARGE: &str = "Blob is too large.";
+pub const UPLOAD_OFFSET_MISMATCH: &str = "Chunk does not continue the upload.";
+pub const NOTIFICATIONS_DISABLED: &str = "Push notifications are disabled on this server.";
+pub const SEALED_SENDER_DISABLED: &str = "Sealed sender is disabled on this server.";
+pub const INVALID_DELIVERY_TOKEN: &str = "Invalid delivery token.";
+
+/// How long before a line expires its online members are warned, at most.
+const EXPIRY_WARNING: u64 = 3_600;
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Blobs whose metadata is checked in one round trip by `sweep_blobs`.
+const SWEEP_BATCH: usize = 500;
+/// How long an offer may ring before the call times out.
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+const CALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the lines are counted for the metrics, which is too costly to do on every scrape.
+const LINE_COUNT_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the index of lines of each online sender is kept from expiring.
+const INDEX_REFRESH_INTERVAL: Duration = Duration::from_secs(3_600);
+
+/// Device of clients that don't name one.
+pub const DEFAULT_DEVICE: &str = "default";
+const MAX_DEVICE_LEN: usize = 64;
 
 pub enum BehaviorAfterReceiveMessage {
     SendToAnotherSender,
     PushedToQueue,
+    /// Sent to a group nobody else is in.
+    NoRecipient,
 }
 
 pub fn sender_to_string(sender: Sender) -> Result<String, String> {
@@ -26,177 +86,1325 @@ pub fn sender_to_string(sender: Sender) -> Result<String, String> {
     }
 }
 
+/// A sender as it may appear in the logs.
+fn log_sender(sender: Sender) -> String {
+    redact(String::from_utf8_lossy(&sender))
+}
+
 pub fn string_to_sender(sender: String) -> Result<Sender, String> {
-    match sender.as_bytes().len() {
+    match sender.len() {
         64 => Ok(Sender::try_from(sender.as_bytes()).unwrap()),
         _ => Err("Sender must be 64 bytes".to_string()),
     }
 }
 
+/// A device is named by its client, e.g. `phone`. It only has to be stable across reconnects.
+pub fn validate_device(device: &str) -> Result<(), String> {
+    let valid = !device.is_empty()
+        && device.len() <= MAX_DEVICE_LEN
+        && device.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(ILLEGAL_INPUT.to_string()),
+    }
+}
+
+/// A connected WebSocket session and the line it joined.
+struct Session {
+    line_id: u16,
+    recipient: Recipient<ServerMessage>,
+}
+
 pub struct Core {
-    online: HashSet<Sender>,
+    /// Online senders and the sessions of each of their devices.
+    online: Mutex<HashMap<Sender, Vec<Session>>>,
     queue: Queue,
     line_manager: LineManager,
-    profile: Profile,
+    live_config: Arc<LiveConfig>,
+    /// Set on shutdown, no session may join from then on.
+    draining: AtomicBool,
+    /// Present when running as one of several instances.
+    cluster: Option<Cluster>,
+    blob_store: BlobStore,
+    blob_index: BlobIndex,
+    push_endpoints: PushEndpoints,
+    /// Present when notifications are enabled.
+    wake_ups: Option<WakeUps>,
+    /// Lines whose online members were told that the line expires soon.
+    warned_lines: Mutex<HashSet<u16>>,
+    /// Lines with an offer seen by this instance that is not answered yet, and when it was made.
+    ringing: Mutex<HashMap<u16, Instant>>,
+}
+
+/// What a member may see about one of their lines. The other members are only counted,
+/// since their identifiers are their credentials.
+#[derive(Debug, Serialize)]
+pub struct MemberLine {
+    pub line_id: u16,
+    pub group: bool,
+    pub members: usize,
+    pub max_members: usize,
+    /// Other members with a session on any instance.
+    pub peers_online: usize,
+    /// Messages the device has not acknowledged yet, sealed ones included.
+    pub queued: u64,
+}
+
+/// What an admin may see about a line. Never includes message content.
+#[derive(Debug, Serialize)]
+pub struct LineSummary {
+    pub line_id: u16,
+    pub occupancy: usize,
+    pub max_members: usize,
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LineDetails {
+    pub line_id: u16,
+    pub max_members: usize,
+    pub ttl: Option<u64>,
+    pub senders: Vec<SenderQueueInfo>,
+}
+
+/// A member is named by its label, see `AtRest::member_label`.
+#[derive(Debug, Serialize)]
+pub struct SenderQueueInfo {
+    pub member: String,
+    pub online: bool,
+    pub queue_depth: u64,
+    pub queue_ttl: Option<u64>,
 }
 
 pub enum JoinLineResult {
-    Refresh,
     BeTheFirst,
     BeTheSecond(Vec<Message>),
+    JoinTheGroup(Vec<Message>),
     Rejoin(Vec<Message>),
 }
 
 impl Core {
     pub fn new(config: LoadResult) -> Self {
         Core {
-            online: HashSet::new(),
+            online: Mutex::new(HashMap::new()),
             queue: config.queue,
             line_manager: config.line_manager,
-            profile: config.profile,
+            live_config: config.live_config,
+            draining: AtomicBool::new(false),
+            cluster: config.cluster,
+            blob_store: config.blob_store,
+            blob_index: config.blob_index,
+            push_endpoints: config.push_endpoints,
+            wake_ups: config.wake_ups,
+            warned_lines: Mutex::new(HashSet::new()),
+            ringing: Mutex::new(HashMap::new()),
         }
     }
     // fn new
-    pub fn join_line(&mut self, sender: Sender, line_id: u16) -> Result<JoinLineResult, String> {
-        // log
-        info!("{} join line {}", sender_to_string(sender).unwrap(), line_id);
+    /// `max_members` opens the line as a group, if nobody is in it yet.
+    /// `have_up_to` is the sequence number of the last queued message the device received,
+    /// the queue is replayed after it.
+    pub fn join_line(&self, sender: Sender, device: &str, line_id: u16, max_members: Option<usize>, have_up_to: Option<u64>, session: Recipient<ServerMessage>) -> Result<JoinLineResult, String> {
+        let result = self.try_join_line(sender, device, line_id, max_members, have_up_to, session.clone());
+        let outcome = match &result {
+            Ok(JoinLineResult::BeTheFirst) => "be_the_first",
+            Ok(JoinLineResult::BeTheSecond(_)) => "be_the_second",
+            Ok(JoinLineResult::JoinTheGroup(_)) => "join_the_group",
+            Ok(JoinLineResult::Rejoin(_)) => "rejoin",
+            Err(e) if e == TRY_TO_JOIN_BUSY_LINE => "busy",
+            Err(e) if e == REGISTRATION_CLOSED => "registration_closed",
+            Err(e) if e == KICKED_FROM_LINE => "kicked",
+            Err(_) => "error",
+        };
+        metrics().join_line_results.with_label_values(&[self.queue.backend(), outcome]).inc();
+        self.update_online_gauge();
+        if let Ok(joined) = &result {
+            self.after_join(sender, line_id, joined, &session);
+        }
+        result
+    }
 
-        // When the sender is already online, return true.
-        if self.is_online(sender) {
-            info!("{} is already online, but tried to join again", sender_to_string(sender).unwrap());
-            return Ok(JoinLineResult::Refresh);
+    /// Tell the other members about the join, and hand the session the events kept for it.
+    fn after_join(&self, sender: Sender, line_id: u16, joined: &JoinLineResult, session: &Recipient<ServerMessage>) {
+        let member = String::from_utf8_lossy(&sender).into_owned();
+        let peer = self.line_manager.member_label(line_id, &member);
+        match joined {
+            JoinLineResult::BeTheFirst => {}
+            JoinLineResult::BeTheSecond(_) | JoinLineResult::JoinTheGroup(_) => {
+                self.notify_members(line_id, &member, ServerMessage::PeerJoined { line_id, peer }, true);
+            }
+            JoinLineResult::Rejoin(_) => {
+                if self.sessions_in_line(sender, line_id) == 1 {
+                    self.notify_members(line_id, &member, ServerMessage::PeerOnline { line_id, peer }, false);
+                }
+            }
+        }
+
+        let events = match self.queue.pop_events(line_id, &member) {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Failed to get queued events: {}", e);
+                return;
+            }
+        };
+        for event in events {
+            match serde_json::from_str::<ServerMessage>(&event) {
+                Ok(event) => session.do_send(event),
+                Err(e) => debug!("Dropping malformed queued event: {}", e),
+            }
+        }
+    }
+
+    fn try_join_line(&self, sender: Sender, device: &str, line_id: u16, max_members: Option<usize>, have_up_to: Option<u64>, session: Recipient<ServerMessage>) -> Result<JoinLineResult, String> {
+        if self.is_draining() {
+            return Err(SERVER_RESTARTING.to_string());
         }
+        validate_device(device)?;
+
+        // log
+        info!("{} join line {} from device {}", log_sender(sender), redact(line_id), redact(device));
 
-        self.online.insert(sender);
+        let sender_id = sender;
         let sender = sender_to_string(sender)?;
-        match self.line_manager.add_sender(sender.clone(), line_id) {
+
+        // When registration is closed, nobody may open a new line.
+        if !self.live_config.current().config.open_registration {
+            match self.line_manager.get_senders(line_id) {
+                Ok(senders) if senders.is_empty() => {
+                    info!("{} try to open line {} while registration is closed", redact(&sender), redact(line_id));
+                    return Err(REGISTRATION_CLOSED.to_string());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to get senders: {}", e);
+                    return Err(INTERNAL_SERVER_ERROR.to_string());
+                }
+            }
+        }
+
+        // Opening a group makes its first member the admin. Joining an existing line ignores it.
+        if let Some(max_members) = max_members {
+            if !(LINE_MEMBERS..=self.live_config.current().config.max_group_members).contains(&max_members) {
+                return Err(INVALID_GROUP_SIZE.to_string());
+            }
+        }
+
+        let joined: Option<fn(Vec<Message>) -> JoinLineResult> = match self.line_manager.add_sender(sender.clone(), line_id, max_members) {
 
             // When Sender is the first sender. Just add he to senders list.
-            Ok(AddSenderActuallyDone::AddTheFirstSender) => Ok(JoinLineResult::BeTheFirst),
+            Ok(AddSenderActuallyDone::AddTheFirstSender) => None,
 
-            // When Sender is the second sender. Get the messages from the queue.
-            Ok(AddSenderActuallyDone::AddTheSecondSender) => {
-                // get the messages from the queue
-                let another_sender = &self.line_manager.get_senders(line_id)?[0];
-                let messages = match &self.queue {
-                    Queue::Redis(q) => {
-                        q.pop_all(line_id, another_sender)
-                    }
-                };
-                debug!("{} get messages from queue", sender.clone());
-                match messages {
-                    Ok(messages) => Ok(JoinLineResult::BeTheSecond(messages)),
-                    Err(e) => {
-                        error!("Failed to get messages from queue: {}", e);
-                        Err(INTERNAL_SERVER_ERROR.to_string())
-                    }
-                }
+            // When Sender opened a group, of which they are the admin.
+            Ok(AddSenderActuallyDone::OpenTheGroup) => {
+                info!("{} opened group line {}", redact(&sender), redact(line_id));
+                None
             }
 
+            // When Sender is the second sender. Get the messages from the queue.
+            Ok(AddSenderActuallyDone::AddTheSecondSender) => Some(JoinLineResult::BeTheSecond),
+
+            // When Sender joins a group that already has two members or more.
+            Ok(AddSenderActuallyDone::AddAnotherMember) => Some(JoinLineResult::JoinTheGroup),
+
             // When Sender is already in the senders list. Get the messages from the queue.
-            Ok(AddSenderActuallyDone::AlreadyInLine) => {
-                // get the message from another sender
-                let senders = self.line_manager.get_senders(line_id)?;
-                let another_sender = if senders[0] == sender {
-                    senders[1].clone()
-                } else {
-                    senders[0].clone()
-                };
-                let messages = match &self.queue {
-                    Queue::Redis(q) => {
-                        q.pop_all(line_id, &another_sender)
-                    }
-                };
-                debug!("{} get messages from queue", sender);
-                match messages {
-                    Ok(messages) => Ok(JoinLineResult::Rejoin(messages)),
-                    Err(e) => {
-                        error!("Failed to get messages from queue: {}", e);
-                        Err(INTERNAL_SERVER_ERROR.to_string())
-                    }
-                }
-            }
+            Ok(AddSenderActuallyDone::AlreadyInLine) => Some(JoinLineResult::Rejoin),
 
-            // When Sender is the third sender. Return error.
-            Ok(AddSenderActuallyDone::TryToAddTheThirdSender) => {
+            // When the line has no room left. Return error.
+            Ok(AddSenderActuallyDone::LineIsFull) => {
                 // return error
-                info!("{} try to join busy line {}", sender, line_id);
-                Err(TRY_TO_JOIN_BUSY_LINE.to_string())
+                info!("{} try to join busy line {}", redact(&sender), redact(line_id));
+                return Err(TRY_TO_JOIN_BUSY_LINE.to_string());
+            }
+
+            // When Sender was kicked from the group. Return error.
+            Ok(AddSenderActuallyDone::Banned) => {
+                info!("{} try to join line {} after being kicked", redact(&sender), redact(line_id));
+                return Err(KICKED_FROM_LINE.to_string());
             }
 
             // Internal server error.
             Err(e) => {
                 error!("Failed to add sender to line: {}", e);
-                Err(INTERNAL_SERVER_ERROR.to_string())
+                return Err(INTERNAL_SERVER_ERROR.to_string());
+            }
+        };
+
+        // Only members are registered, so a refused join leaves nothing for `set_offline` to announce.
+        let first_device = {
+            let mut online = self.online.lock().unwrap();
+            // Checked again under the lock, so no session is left behind by `start_draining`.
+            if self.is_draining() {
+                return Err(SERVER_RESTARTING.to_string());
             }
+            let sessions = online.entry(sender_id).or_default();
+            sessions.push(Session { line_id, recipient: session });
+            sessions.len() == 1
+        };
+        // Only the first device announces the sender, the others share its presence.
+        if first_device {
+            self.announce_online(&sender);
+        }
+        match joined {
+            Some(joined) => self.replay_inbox(&sender, line_id, device, have_up_to).map(joined),
+            None => Ok(JoinLineResult::BeTheFirst),
         }
     }
     // fn join_line
-    pub fn set_offline(&mut self, sender: Sender) {
-        info!("{} offline", sender_to_string(sender).unwrap());
-        self.online.remove(&sender);
+    /// Called when `session` closes. The sender stays online while another device is.
+    pub fn set_offline(&self, sender: Sender, session: &Recipient<ServerMessage>) {
+        let (line_id, last_device) = {
+            let mut online = self.online.lock().unwrap();
+            let sessions = match online.get_mut(&sender) {
+                Some(sessions) => sessions,
+                None => return,
+            };
+            let line_id = match sessions.iter().position(|s| &s.recipient == session) {
+                Some(index) => sessions.remove(index).line_id,
+                None => return,
+            };
+            let last_device = sessions.is_empty();
+            if last_device {
+                online.remove(&sender);
+            }
+            (line_id, last_device)
+        };
+        if last_device {
+            info!("{} offline", log_sender(sender));
+            self.announce_offline(sender);
+        }
+        self.update_online_gauge();
+        if self.sessions_in_line(sender, line_id) == 0 {
+            let member = String::from_utf8_lossy(&sender).into_owned();
+            let peer = self.line_manager.member_label(line_id, &member);
+            self.notify_members(line_id, &member, ServerMessage::PeerOffline { line_id, peer }, false);
+        }
     }
-    pub fn is_online(&self, sender: Sender) -> bool {
-        self.online.contains(&sender)
+
+    fn sessions_in_line(&self, sender: Sender, line_id: u16) -> usize {
+        self.online.lock().unwrap().get(&sender).map_or(0, |sessions| sessions.iter().filter(|s| s.line_id == line_id).count())
     }
 
-    pub fn exit_line(&mut self, sender: Sender, line_id: u16) -> Result<(), String> {
-        info!("{} exit line {}", sender_to_string(sender).unwrap(), line_id);
-        self.set_offline(sender);
-        let sender = sender_to_string(sender)?;
-        self.line_manager.remove_sender(sender, line_id)
+    /// Tell the members of a line other than `sender` about `event`, on every instance.
+    /// Members who are not online get it queued when `queue_if_offline`,
+    /// presence changes are not worth keeping for later.
+    fn notify_members(&self, line_id: u16, sender: &str, event: ServerMessage, queue_if_offline: bool) {
+        let senders = match self.line_manager.get_senders(line_id) {
+            Ok(senders) => senders,
+            Err(e) => {
+                error!("Failed to get senders: {}", e);
+                return;
+            }
+        };
+        for member in senders.iter().filter(|s| *s != sender) {
+            let member_id = match string_to_sender(member.clone()) {
+                Ok(member_id) => member_id,
+                Err(_) => continue,
+            };
+            let delivered_here = self.send_local(member_id, &event);
+            let delivered_elsewhere = self.publish_to_cluster(member_id, ClusterEvent::Notify(event.clone()));
+            if delivered_here || delivered_elsewhere || !queue_if_offline {
+                continue;
+            }
+            let queued = serde_json::to_string(&event)
+                .map_err(|e| e.to_string())
+                .and_then(|event| self.queue.push_event(line_id, member, event));
+            if let Err(e) = queued {
+                error!("Failed to queue event: {}", e);
+            }
+        }
     }
 
-    pub fn receive_message(&mut self, message: &Message) -> Result<BehaviorAfterReceiveMessage, String> {
-        /// If the sender who is in the same line with the `message.sender`
-        /// is online, send the message to him.
-        /// else, push the message to the queue.
+    fn announce_online(&self, sender: &str) {
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.join(sender) {
+                error!("Failed to announce {} to the cluster: {}", redact(sender), e);
+            }
+        }
+    }
 
-        let Message { sender, line_id, content } = message.clone();
+    fn announce_offline(&self, sender: Sender) {
+        if let Some(cluster) = &self.cluster {
+            if let Err(e) = cluster.leave(&String::from_utf8_lossy(&sender)) {
+                error!("Failed to withdraw {} from the cluster: {}", log_sender(sender), e);
+            }
+        }
+    }
 
-        let sender_line_in = line_id;
-        let another_sender = match self.line_manager.get_senders(*line_id) {
-            Ok(senders) => {
-                match senders.iter().find(|s| **s == sender) {
-                    None => {
-                        return Err(SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN.to_string());
-                    }
-                    _ => {}
+    /// Whether `sender` has a session on this or, in a cluster, any other instance.
+    fn is_present(&self, sender: Sender) -> bool {
+        if self.is_online(sender) {
+            return true;
+        }
+        match &self.cluster {
+            Some(cluster) => cluster.is_online(&String::from_utf8_lossy(&sender)).unwrap_or_else(|e| {
+                error!("Failed to check cluster presence: {}", e);
+                false
+            }),
+            None => false,
+        }
+    }
+
+    fn update_online_gauge(&self) {
+        let sessions: usize = self.online.lock().unwrap().values().map(Vec::len).sum();
+        metrics().online_sessions.with_label_values(&[self.queue.backend()]).set(sessions as i64);
+    }
+
+    /// Send `message` to every device of `sender` online on this instance.
+    /// Returns whether any of them took it.
+    fn deliver_local(&self, sender: Sender, message: &Message) -> bool {
+        self.send_local(sender, &ServerMessage::PushChatMessages(vec![message.clone()]))
+    }
+
+    fn send_local(&self, sender: Sender, msg: &ServerMessage) -> bool {
+        let online = self.online.lock().unwrap();
+        let sessions = match online.get(&sender) {
+            Some(sessions) => sessions,
+            None => return false,
+        };
+        let mut delivered = false;
+        for session in sessions {
+            delivered |= session.recipient.try_send(msg.clone()).is_ok();
+        }
+        delivered
+    }
+
+    /// Close every session of `sender` on this instance.
+    fn disconnect_local(&self, sender: Sender, reason: DisconnectReason) -> bool {
+        let sessions = self.online.lock().unwrap().remove(&sender);
+        match sessions {
+            Some(sessions) => {
+                for session in sessions {
+                    session.recipient.do_send(ServerMessage::Disconnect(reason));
                 }
+                self.announce_offline(sender);
+                self.update_online_gauge();
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn is_online(&self, sender: Sender) -> bool {
+        self.online.lock().unwrap().contains_key(&sender)
+    }
+
+    /// Leave a line for good. The sessions of `sender` in it are closed, on every instance.
+    pub fn exit_line(&self, sender: Sender, line_id: u16) -> Result<(), String> {
+        let member = sender_to_string(sender)?;
+        self.check_member(&member, line_id)?;
+        info!("{} exit line {}", log_sender(sender), redact(line_id));
+        self.line_manager.remove_sender(member.clone(), line_id).map_err(|e| Self::internal_error("remove sender", e))?;
+        let peer = self.line_manager.member_label(line_id, &member);
+        self.notify_members(line_id, &member, ServerMessage::PeerLeft { line_id, peer }, true);
+        self.close_line_sessions(sender, line_id);
+        self.publish_to_cluster(sender, ClusterEvent::Leave(line_id));
+        Ok(())
+    }
 
-                // only 1 sender, push to the queue
-                if senders.len() == 1 {
-                    self.queue.push(message)?;
-                    return Ok(BehaviorAfterReceiveMessage::PushedToQueue);
+    /// Close the sessions of `sender` in a line on this instance, after it left the line.
+    fn close_line_sessions(&self, sender: Sender, line_id: u16) {
+        let last_device = {
+            let mut online = self.online.lock().unwrap();
+            let sessions = match online.get_mut(&sender) {
+                Some(sessions) => sessions,
+                None => return,
+            };
+            sessions.retain(|session| {
+                if session.line_id == line_id {
+                    session.recipient.do_send(ServerMessage::Disconnect(DisconnectReason::LeftLine));
                 }
-                if senders[0] == sender {
-                    senders[1].clone()
-                } else {
-                    senders[0].clone()
+                session.line_id != line_id
+            });
+            let last_device = sessions.is_empty();
+            if last_device {
+                online.remove(&sender);
+            }
+            last_device
+        };
+        if last_device {
+            self.announce_offline(sender);
+        }
+        self.update_online_gauge();
+    }
+
+    pub fn receive_message(&self, message: &Message) -> Result<BehaviorAfterReceiveMessage, String> {
+        // The message is queued for every other member of the line, until each of their devices
+        // acknowledged it, and those who are online also get it on each of their sessions.
+
+        let Message { sender, line_id, content, ttl, .. } = message;
+
+        if content.len() > self.live_config.current().config.max_message_size {
+            return Err(MESSAGE_TOO_LARGE.to_string());
+        }
+
+        // a message that expires immediately can never be delivered
+        if *ttl == Some(0) {
+            return Err(ILLEGAL_INPUT.to_string());
+        }
+
+        let senders = match self.line_manager.get_senders(*line_id) {
+            Ok(senders) => senders,
+            Err(e) => {
+                error!("Failed to get senders: {}", e);
+                return Err(INTERNAL_SERVER_ERROR.to_string());
+            }
+        };
+        if !senders.contains(sender) {
+            return Err(SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN.to_string());
+        }
+
+        // sending a message counts as using the line
+        if let Err(e) = self.line_manager.refresh_ttl(*line_id) {
+            error!("Failed to refresh line TTL: {}", e);
+            return Err(INTERNAL_SERVER_ERROR.to_string());
+        }
+        metrics().messages_received.with_label_values(&[self.queue.backend()]).inc();
+
+        let group = self.line_manager.group(*line_id).map_err(|e| Self::internal_error("get group", e))?;
+        let recipients: Vec<&String> = senders.iter().filter(|s| *s != sender).collect();
+
+        // only 1 sender in a line of two, push to the queue for whoever joins next.
+        // A group only fans out to its current members.
+        if recipients.is_empty() {
+            if group.is_some() {
+                return Ok(BehaviorAfterReceiveMessage::NoRecipient);
+            }
+            self.queue.push(sender, message.clone())?;
+            metrics().messages_queued.with_label_values(&[self.queue.backend()]).inc();
+            return Ok(BehaviorAfterReceiveMessage::PushedToQueue);
+        }
+
+        let mut behavior = BehaviorAfterReceiveMessage::SendToAnotherSender;
+        for recipient in recipients {
+            let recipient_id = match string_to_sender(recipient.clone()) {
+                Ok(sender) => sender,
+                Err(e) => {
+                    debug!("Failed to convert string to sender: {}", e);
+                    return Err(ILLEGAL_INPUT.to_string());
                 }
+            };
+            let queue_owner = if group.is_some() { recipient } else { sender };
+            if !self.queue_and_deliver(queue_owner, recipient_id, message)? {
+                self.wake(recipient);
+                behavior = BehaviorAfterReceiveMessage::PushedToQueue;
             }
+        }
+        Ok(behavior)
+    }
+
+    /// Queue a message for `recipient`, so every device of theirs gets it, even those offline now.
+    /// Every online device also gets it right away, on this instance and, in a cluster, on the others.
+    /// Returns whether any of them did, sessions whose mailbox is full or closed can't take it.
+    fn queue_and_deliver(&self, queue_owner: &str, recipient: Sender, message: &Message) -> Result<bool, String> {
+        let seq = self.queue.push(queue_owner, message.clone())?;
+        metrics().messages_queued.with_label_values(&[self.queue.backend()]).inc();
+        let sender = self.line_manager.sender_label(message.line_id, &message.sender);
+        let message = Message { sender, seq: Some(seq), ..message.clone() };
+        let delivered_here = self.deliver_local(recipient, &message);
+        let delivered_elsewhere = self.publish_to_cluster(recipient, ClusterEvent::Deliver(message));
+        let delivered = delivered_here || delivered_elsewhere;
+        if delivered {
+            metrics().messages_delivered_live.with_label_values(&[self.queue.backend()]).inc();
+        }
+        Ok(delivered)
+    }
+
+    fn sealed_sender_secret(&self) -> Result<String, String> {
+        match &self.live_config.current().config.sealed_sender_secret {
+            Some(secret) if !secret.is_empty() => Ok(secret.clone()),
+            _ => Err(SEALED_SENDER_DISABLED.to_string()),
+        }
+    }
+
+    /// The token that lets anyone deliver sealed messages to `sender` in a line.
+    pub fn delivery_token(&self, sender: Sender, line_id: u16) -> Result<String, String> {
+        let secret = self.sealed_sender_secret()?;
+        let sender = sender_to_string(sender)?;
+        self.check_member(&sender, line_id)?;
+        Ok(sealed::delivery_token(&secret, line_id, &sender))
+    }
+
+    /// Deliver a message whose sender is unknown to the member of its line who handed out `token`.
+    /// The token names the recipient, whose identifier is also its credential and is never sent.
+    /// Devices acknowledge it with `sealed`, like the sealed messages they fetch.
+    pub fn receive_sealed(&self, token: &str, message: &Message) -> Result<BehaviorAfterReceiveMessage, String> {
+        let secret = self.sealed_sender_secret()?;
+        let Message { line_id, content, ttl, .. } = message;
+
+        if content.len() > self.live_config.current().config.max_message_size {
+            return Err(MESSAGE_TOO_LARGE.to_string());
+        }
+        if *ttl == Some(0) {
+            return Err(ILLEGAL_INPUT.to_string());
+        }
+        let senders = self.line_manager.get_senders(*line_id).map_err(|e| Self::internal_error("get senders", e))?;
+        let recipient = match senders.iter().find(|s| sealed::verify_delivery_token(&secret, *line_id, s, token)) {
+            Some(recipient) => recipient,
+            None => return Err(INVALID_DELIVERY_TOKEN.to_string()),
+        };
+        let recipient_id = string_to_sender(recipient.to_string()).map_err(|_| INVALID_DELIVERY_TOKEN.to_string())?;
+
+        if let Err(e) = self.line_manager.refresh_ttl(*line_id) {
+            error!("Failed to refresh line TTL: {}", e);
+            return Err(INTERNAL_SERVER_ERROR.to_string());
+        }
+        metrics().messages_received.with_label_values(&[self.queue.backend()]).inc();
+
+        if self.queue_and_deliver(&sealed_queue_owner(recipient), recipient_id, message)? {
+            return Ok(BehaviorAfterReceiveMessage::SendToAnotherSender);
+        }
+        self.wake(recipient);
+        Ok(BehaviorAfterReceiveMessage::PushedToQueue)
+    }
+
+    /// Forward a signal to the other members of its line who are online, on any instance.
+    /// Nothing is written to storage, a signal nobody is online for is dropped.
+    /// Returns whether any member got it, call signals fail instead when the peer is offline.
+    pub fn relay_signal(&self, signal: &Signal) -> Result<bool, String> {
+        if signal.content.len() > self.live_config.current().config.max_message_size {
+            return Err(MESSAGE_TOO_LARGE.to_string());
+        }
+        if signal.kind.is_call() && self.max_members(signal.line_id)? != LINE_MEMBERS {
+            return Err(CALLS_NEED_TWO_MEMBERS.to_string());
+        }
+        let senders = match self.line_manager.get_senders(signal.line_id) {
+            Ok(senders) => senders,
             Err(e) => {
                 error!("Failed to get senders: {}", e);
                 return Err(INTERNAL_SERVER_ERROR.to_string());
             }
         };
+        if !senders.contains(&signal.sender) {
+            return Err(SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN.to_string());
+        }
+
+        let sender = self.line_manager.member_label(signal.line_id, &signal.sender);
+        let event = ServerMessage::Signal(Signal { sender, ..signal.clone() });
+        let mut relayed = false;
+        for member in senders.iter().filter(|s| **s != signal.sender) {
+            let member_id = match string_to_sender(member.clone()) {
+                Ok(member_id) => member_id,
+                Err(_) => continue,
+            };
+            relayed |= self.send_local(member_id, &event);
+            relayed |= self.publish_to_cluster(member_id, ClusterEvent::Notify(event.clone()));
+        }
+        let outcome = if relayed { "relayed" } else { "dropped" };
+        metrics().signals.with_label_values(&[signal.kind.name(), outcome]).inc();
+        if signal.kind.is_call() {
+            if !relayed {
+                return Err(PEER_OFFLINE.to_string());
+            }
+            self.track_call(signal);
+        }
+        Ok(relayed)
+    }
+
+    /// Both the instance of the caller and that of the callee see every call signal,
+    /// so each times out the calls of its own sessions.
+    fn track_call(&self, signal: &Signal) {
+        match signal.kind {
+            SignalKind::Offer => {
+                self.ringing.lock().unwrap().insert(signal.line_id, Instant::now());
+            }
+            SignalKind::Answer | SignalKind::Hangup => {
+                self.ringing.lock().unwrap().remove(&signal.line_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Tell the sessions of calls that rang too long that they timed out.
+    pub fn expire_calls(&self) {
+        let mut expired = Vec::new();
+        self.ringing.lock().unwrap().retain(|line_id, since| {
+            let timed_out = since.elapsed() >= CALL_TIMEOUT;
+            if timed_out {
+                expired.push(*line_id);
+            }
+            !timed_out
+        });
+        let online = self.online.lock().unwrap();
+        for line_id in expired {
+            debug!("Call in line {} timed out", redact(line_id));
+            let timed_out = ServerMessage::CallTimedOut { line_id };
+            for session in online.values().flatten().filter(|s| s.line_id == line_id) {
+                session.recipient.do_send(timed_out.clone());
+            }
+        }
+    }
+
+    /// Hand an event to the instance holding the session of `sender`, if any.
+    fn publish_to_cluster(&self, sender: Sender, event: ClusterEvent) -> bool {
+        match &self.cluster {
+            Some(cluster) => cluster.publish(&String::from_utf8_lossy(&sender), &event).unwrap_or_else(|e| {
+                error!("Failed to publish to the cluster: {}", e);
+                false
+            }),
+            None => false,
+        }
+    }
 
-        // if the another sender is online, send the message to him.
-        return if self.is_online(match string_to_sender(another_sender) {
+    /// Handle an event another instance published for a session on this one.
+    pub fn handle_cluster_event(&self, sender: String, event: ClusterEvent) {
+        let sender_id = match string_to_sender(sender.clone()) {
             Ok(sender) => sender,
+            Err(_) => return,
+        };
+        match event {
+            ClusterEvent::Deliver(message) => {
+                // The sessions went away after the message was published, it waits in the queue.
+                if !self.deliver_local(sender_id, &message) {
+                    self.wake(&sender);
+                }
+            }
+            ClusterEvent::Notify(event) => {
+                if let ServerMessage::Signal(signal) = &event {
+                    if signal.kind.is_call() {
+                        self.track_call(signal);
+                    }
+                }
+                self.send_local(sender_id, &event);
+            }
+            ClusterEvent::Disconnect(reason) => {
+                self.disconnect_local(sender_id, reason);
+            }
+            ClusterEvent::Leave(line_id) => {
+                self.close_line_sessions(sender_id, line_id);
+            }
+        }
+    }
+
+    /// The queue holding the messages for `sender` in a line: the queue of the other sender
+    /// in a line of two, `None` while `sender` is alone, and `sender`'s own queue in a group.
+    fn inbox(&self, sender: &String, line_id: u16) -> Result<Option<String>, String> {
+        let senders = match self.line_manager.get_senders(line_id) {
+            Ok(senders) => senders,
+            Err(e) => {
+                error!("Failed to get senders: {}", e);
+                return Err(INTERNAL_SERVER_ERROR.to_string());
+            }
+        };
+        if !senders.contains(sender) {
+            return Err(SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN.to_string());
+        }
+        match self.line_manager.group(line_id) {
+            Ok(Some(_)) => Ok(Some(sender.clone())),
+            Ok(None) => Ok(senders.into_iter().find(|s| s != sender)),
+            Err(e) => Err(Self::internal_error("get group", e)),
+        }
+    }
+
+    /// Everything queued for `sender` in a line that `device` has not received yet.
+    fn replay_inbox(&self, sender: &String, line_id: u16, device: &str, have_up_to: Option<u64>) -> Result<Vec<Message>, String> {
+        let messages = match self.inbox(sender, line_id)? {
+            Some(inbox) => self.queue.replay(line_id, &inbox, device, have_up_to),
+            None => Ok(Vec::new()),
+        };
+        debug!("{} get messages from queue", redact(sender));
+        messages.map_err(|e| Self::internal_error("get messages from queue", e))
+    }
+
+    /// The queue `sender` reads in a line: its inbox, or the sealed messages sent to it.
+    fn mailbox(&self, sender: &String, line_id: u16, sealed: bool) -> Result<Option<String>, String> {
+        if sealed {
+            self.check_member(sender, line_id)?;
+            return Ok(Some(sealed_queue_owner(sender)));
+        }
+        self.inbox(sender, line_id)
+    }
+
+    /// Read the messages queued for `sender` in a line, without removing them.
+    /// Without a `cursor`, reading starts after what `device` has acknowledged.
+    pub fn fetch_queued(&self, sender: Sender, device: &str, line_id: u16, sealed: bool, cursor: Option<u64>, limit: usize) -> Result<QueuedMessages, String> {
+        validate_device(device)?;
+        let sender = sender_to_string(sender)?;
+        let inbox = match self.mailbox(&sender, line_id, sealed)? {
+            Some(inbox) => inbox,
+            None => return Ok(QueuedMessages { messages: Vec::new(), next_cursor: cursor.unwrap_or(0) }),
+        };
+        // Also registers a device fetching for the first time, even from a cursor of its own.
+        let device_cursor = self.queue.device_cursor(line_id, &inbox, device)
+            .map_err(|e| Self::internal_error("get device cursor", e))?;
+        let cursor = cursor.unwrap_or(device_cursor);
+        self.queue.fetch(line_id, &inbox, cursor, limit).map_err(|e| {
+            error!("Failed to get messages from queue: {}", e);
+            INTERNAL_SERVER_ERROR.to_string()
+        })
+    }
+
+    /// Acknowledge the messages queued for `sender` in a line before `cursor` on behalf of `device`.
+    pub fn ack_queued(&self, sender: Sender, device: &str, line_id: u16, sealed: bool, cursor: u64) -> Result<(), String> {
+        validate_device(device)?;
+        let sender = sender_to_string(sender)?;
+        let inbox = match self.mailbox(&sender, line_id, sealed)? {
+            Some(inbox) => inbox,
+            None => return Ok(()),
+        };
+        self.queue.ack(line_id, &inbox, device, cursor).map_err(|e| {
+            error!("Failed to acknowledge messages: {}", e);
+            INTERNAL_SERVER_ERROR.to_string()
+        })
+    }
+
+    fn internal_error(action: &str, e: String) -> String {
+        error!("Failed to {}: {}", action, e);
+        INTERNAL_SERVER_ERROR.to_string()
+    }
+
+    /// The lines `sender` is a member of, so a new device can rebuild its conversation list.
+    pub fn lines_of(&self, sender: Sender, device: &str) -> Result<Vec<MemberLine>, String> {
+        validate_device(device)?;
+        let sender = sender_to_string(sender)?;
+        let line_ids = self.line_manager.lines_of(&sender).map_err(|e| Self::internal_error("list lines of sender", e))?;
+        let mut lines = Vec::with_capacity(line_ids.len());
+        for line_id in line_ids {
+            let senders = self.line_manager.get_senders(line_id).map_err(|e| Self::internal_error("get senders", e))?;
+            // Left in the index by a line that expired.
+            if !senders.contains(&sender) {
+                continue;
+            }
+            let group = self.line_manager.group(line_id).map_err(|e| Self::internal_error("get group", e))?;
+            let peers_online = senders.iter()
+                .filter(|s| **s != sender)
+                .filter_map(|s| string_to_sender(s.clone()).ok())
+                .filter(|s| self.is_present(*s))
+                .count();
+            let mut queued = self.queue.unacked(line_id, &sealed_queue_owner(&sender), device)
+                .map_err(|e| Self::internal_error("count unacknowledged messages", e))?;
+            if let Some(inbox) = self.inbox(&sender, line_id)? {
+                queued += self.queue.unacked(line_id, &inbox, device)
+                    .map_err(|e| Self::internal_error("count unacknowledged messages", e))?;
+            }
+            lines.push(MemberLine {
+                line_id,
+                group: group.is_some(),
+                members: senders.len(),
+                max_members: group.map_or(LINE_MEMBERS, |group| group.max_members),
+                peers_online,
+                queued,
+            });
+        }
+        Ok(lines)
+    }
+
+    pub fn list_lines(&self) -> Result<Vec<LineSummary>, String> {
+        let lines = self.line_manager.list_lines().map_err(|e| Self::internal_error("list lines", e))?;
+        let mut summaries = Vec::with_capacity(lines.len());
+        for line_id in lines {
+            let senders = self.line_manager.get_senders(line_id).map_err(|e| Self::internal_error("get senders", e))?;
+            let ttl = self.line_manager.ttl(line_id).map_err(|e| Self::internal_error("get line TTL", e))?;
+            let max_members = self.max_members(line_id)?;
+            summaries.push(LineSummary { line_id, occupancy: senders.len(), max_members, ttl });
+        }
+        Ok(summaries)
+    }
+
+    pub fn line_details(&self, line_id: u16) -> Result<LineDetails, String> {
+        let senders = self.line_manager.get_senders(line_id).map_err(|e| Self::internal_error("get senders", e))?;
+        let ttl = self.line_manager.ttl(line_id).map_err(|e| Self::internal_error("get line TTL", e))?;
+        let mut infos = Vec::with_capacity(senders.len());
+        for sender in senders {
+            let queue_depth = self.queue.depth(line_id, &sender).map_err(|e| Self::internal_error("get queue depth", e))?;
+            let queue_ttl = self.queue.ttl(line_id, &sender).map_err(|e| Self::internal_error("get queue TTL", e))?;
+            let online = string_to_sender(sender.clone()).map(|s| self.is_present(s)).unwrap_or(false);
+            let member = self.line_manager.member_label(line_id, &sender);
+            infos.push(SenderQueueInfo { member, online, queue_depth, queue_ttl });
+        }
+        let max_members = self.max_members(line_id)?;
+        Ok(LineDetails { line_id, max_members, ttl, senders: infos })
+    }
+
+    fn max_members(&self, line_id: u16) -> Result<usize, String> {
+        let group = self.line_manager.group(line_id).map_err(|e| Self::internal_error("get group", e))?;
+        Ok(group.map_or(LINE_MEMBERS, |group| group.max_members))
+    }
+
+    fn group_of_admin(&self, admin: &str, line_id: u16) -> Result<Group, String> {
+        match self.line_manager.group(line_id) {
+            Ok(Some(group)) if group.admin == admin => Ok(group),
+            Ok(_) => Err(NOT_GROUP_ADMIN.to_string()),
+            Err(e) => Err(Self::internal_error("get group", e)),
+        }
+    }
+
+    /// Let the admin of a group remove the member labeled `label`, who may not join again until invited.
+    pub fn kick_member(&self, admin: Sender, line_id: u16, label: String) -> Result<(), String> {
+        let admin = sender_to_string(admin)?;
+        self.group_of_admin(&admin, line_id)?;
+        let member = match self.member_by_label(line_id, &label)? {
+            Some(member) if member == admin => return Err(ILLEGAL_INPUT.to_string()),
+            Some(member) => member,
+            None => return Err(NOT_A_MEMBER.to_string()),
+        };
+        info!("{} kicked from group {}", redact(&member), redact(line_id));
+        self.line_manager.set_banned(line_id, &label, true).map_err(|e| Self::internal_error("ban member", e))?;
+        self.line_manager.remove_sender(member.clone(), line_id).map_err(|e| Self::internal_error("remove sender", e))?;
+        // What was waiting for the member is no longer theirs to read.
+        self.purge_queues_of(line_id, &member)?;
+        self.notify_members(line_id, &member, ServerMessage::PeerLeft { line_id, peer: label }, true);
+        if let Ok(member) = string_to_sender(member) {
+            self.disconnect_local(member, DisconnectReason::Kicked);
+            self.publish_to_cluster(member, ClusterEvent::Disconnect(DisconnectReason::Kicked));
+        }
+        Ok(())
+    }
+
+    /// Let the admin of a group allow the sender labeled `label` to join, lifting an earlier kick.
+    /// Joining still takes the line id, like for any other line.
+    pub fn invite_member(&self, admin: Sender, line_id: u16, label: String) -> Result<(), String> {
+        let admin = sender_to_string(admin)?;
+        self.group_of_admin(&admin, line_id)?;
+        if label.len() != MEMBER_LABEL_LEN || !label.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ILLEGAL_INPUT.to_string());
+        }
+        self.line_manager.set_banned(line_id, &label, false).map_err(|e| Self::internal_error("unban member", e))
+    }
+
+    /// The member of a line labeled `label`, see `AtRest::member_label`.
+    fn member_by_label(&self, line_id: u16, label: &str) -> Result<Option<String>, String> {
+        let senders = self.line_manager.get_senders(line_id).map_err(|e| Self::internal_error("get senders", e))?;
+        Ok(senders.into_iter().find(|sender| self.line_manager.member_label(line_id, sender) == label))
+    }
+
+    /// Remove the member labeled `member` from a line and close their session.
+    pub fn evict_sender(&self, member: String, line_id: u16) -> Result<(), String> {
+        let sender = match self.member_by_label(line_id, &member)? {
+            Some(sender) => sender,
+            None => return Err(SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN.to_string()),
+        };
+        warn!("{} evicted from line {} by admin", redact(&sender), redact(line_id));
+        self.line_manager.remove_sender(sender.clone(), line_id).map_err(|e| Self::internal_error("remove sender", e))?;
+        self.notify_members(line_id, &sender, ServerMessage::PeerLeft { line_id, peer: member }, true);
+        if let Ok(sender) = string_to_sender(sender) {
+            // Devices may be online on this and other instances at once.
+            self.disconnect_local(sender, DisconnectReason::EvictedByAdmin);
+            self.publish_to_cluster(sender, ClusterEvent::Disconnect(DisconnectReason::EvictedByAdmin));
+        }
+        Ok(())
+    }
+
+    /// Drop every message queued in a line.
+    pub fn purge_line(&self, line_id: u16) -> Result<(), String> {
+        let senders = self.line_manager.get_senders(line_id).map_err(|e| Self::internal_error("get senders", e))?;
+        for sender in senders {
+            self.purge_queues_of(line_id, &sender)?;
+        }
+        warn!("Queues of line {} purged by admin", redact(line_id));
+        Ok(())
+    }
+
+    fn purge_queues_of(&self, line_id: u16, sender: &str) -> Result<(), String> {
+        for queue_owner in [sender.to_string(), sealed_queue_owner(sender)] {
+            self.queue.purge(line_id, &queue_owner).map_err(|e| Self::internal_error("purge queue", e))?;
+        }
+        Ok(())
+    }
+
+    fn check_member(&self, sender: &String, line_id: u16) -> Result<Vec<String>, String> {
+        let senders = self.line_manager.get_senders(line_id).map_err(|e| Self::internal_error("get senders", e))?;
+        if !senders.contains(sender) {
+            return Err(SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN.to_string());
+        }
+        Ok(senders)
+    }
+
+    /// Start uploading a blob to a line, returning the id to upload its chunks to.
+    pub fn start_upload(&self, sender: Sender, line_id: u16) -> Result<String, String> {
+        let sender = sender_to_string(sender)?;
+        self.check_member(&sender, line_id)?;
+        let upload_id = new_upload_id();
+        self.blob_index.create_upload(&upload_id, line_id, &sender)
+            .map_err(|e| Self::internal_error("create upload", e))?;
+        Ok(upload_id)
+    }
+
+    /// Append a chunk at `offset`, which has to be the size uploaded so far.
+    /// Returns the new size, so a client can resume an interrupted upload from there.
+    pub fn append_upload(&self, sender: Sender, line_id: u16, upload_id: &str, offset: u64, chunk: &[u8]) -> Result<u64, String> {
+        let sender = sender_to_string(sender)?;
+        let upload = self.upload_of(&sender, line_id, upload_id)?;
+        if offset != upload.size {
+            return Err(UPLOAD_OFFSET_MISMATCH.to_string());
+        }
+        let size = upload.size.saturating_add(chunk.len() as u64);
+        if size > self.live_config.current().blobs.max_blob_size {
+            return Err(BLOB_TOO_LARGE.to_string());
+        }
+        let size = self.blob_store.append(&upload_name(upload_id), chunk, KeyExpiry::ExpireIn(UPLOAD_TTL.as_secs()))
+            .map_err(|e| Self::internal_error("append to upload", e))?;
+        self.blob_index.set_upload_size(upload_id, size)
+            .map_err(|e| Self::internal_error("update upload", e))?;
+        Ok(size)
+    }
+
+    /// Turn an upload into a blob named after its content.
+    pub fn complete_upload(&self, sender: Sender, line_id: u16, upload_id: &str) -> Result<(String, u64), String> {
+        let sender = sender_to_string(sender)?;
+        let upload = self.upload_of(&sender, line_id, upload_id)?;
+        let name = upload_name(upload_id);
+        let blob_id = blob::blob_id(&self.blob_store, &name, upload.size)
+            .map_err(|e| Self::internal_error("read upload", e))?;
+        let auto_delete_time = self.live_config.auto_delete_time().get();
+        self.blob_store.rename(&name, &blob_name(line_id, &blob_id), key_expiry(auto_delete_time))
+            .map_err(|e| Self::internal_error("write blob", e))?;
+        let meta = BlobMeta { uploader: sender, size: upload.size };
+        self.blob_index.add_blob(line_id, &blob_id, &meta)
+            .map_err(|e| Self::internal_error("add blob", e))?;
+        if let Err(e) = self.blob_index.remove_upload(upload_id) {
+            // Expires later.
+            error!("Failed to remove finished upload: {}", e);
+        }
+        debug!("Blob of {} bytes uploaded to line {}", meta.size, redact(line_id));
+        Ok((blob_id, meta.size))
+    }
+
+    fn upload_of(&self, sender: &String, line_id: u16, upload_id: &str) -> Result<blob::index::Upload, String> {
+        match self.blob_index.upload(upload_id) {
+            Ok(Some(upload)) if upload.line_id == line_id && upload.uploader == *sender => Ok(upload),
+            Ok(_) => Err(UPLOAD_NOT_FOUND.to_string()),
+            Err(e) => Err(Self::internal_error("get upload", e)),
+        }
+    }
+
+    /// Start downloading a blob, returning its size. Its content is then read with `read_blob`.
+    pub fn fetch_blob(&self, sender: Sender, line_id: u16, blob_id: &str) -> Result<u64, String> {
+        let sender = sender_to_string(sender)?;
+        self.check_member(&sender, line_id)?;
+        match self.blob_index.blob(line_id, blob_id) {
+            Ok(Some(meta)) => Ok(meta.size),
+            Ok(None) => Err(BLOB_NOT_FOUND.to_string()),
+            Err(e) => Err(Self::internal_error("get blob", e)),
+        }
+    }
+
+    /// The part of a blob from `offset`, once `fetch_blob` allowed the download.
+    pub fn read_blob(&self, line_id: u16, blob_id: &str, offset: u64) -> Result<Vec<u8>, String> {
+        let chunk = self.blob_store.read_range(&blob_name(line_id, blob_id), offset, blob::READ_CHUNK_SIZE)
+            .map_err(|e| Self::internal_error("read blob", e))?;
+        if chunk.is_empty() {
+            // Deleted or expired since the download started.
+            return Err(BLOB_NOT_FOUND.to_string());
+        }
+        Ok(chunk)
+    }
+
+    /// Called once a blob was downloaded in full.
+    /// It is deleted once every other member of the line has fetched it.
+    pub fn blob_fetched(&self, sender: Sender, line_id: u16, blob_id: &str) -> Result<(), String> {
+        let sender = sender_to_string(sender)?;
+        let senders = self.check_member(&sender, line_id)?;
+        let meta = match self.blob_index.blob(line_id, blob_id) {
+            Ok(Some(meta)) => meta,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(Self::internal_error("get blob", e)),
+        };
+        if sender == meta.uploader {
+            return Ok(());
+        }
+
+        let others: Vec<&String> = senders.iter().filter(|s| **s != meta.uploader).collect();
+        let fetched_by_all = self.blob_index.mark_fetched(line_id, blob_id, &sender, &others)
+            .map_err(|e| Self::internal_error("mark blob fetched", e))?;
+        if fetched_by_all {
+            debug!("Blob in line {} fetched by every member, deleting it", redact(line_id));
+            if let Err(e) = self.blob_index.remove_blob(line_id, blob_id)
+                .and_then(|_| self.blob_store.delete(&blob_name(line_id, blob_id))) {
+                error!("Failed to delete fetched blob: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove content whose metadata expired, for stores that do not expire it on their own.
+    pub fn sweep_blobs(&self) {
+        let names = match self.blob_store.list() {
+            Ok(names) => names,
             Err(e) => {
-                debug!("Failed to convert string to sender: {}", e);
-                return Err(ILLEGAL_INPUT.to_string());
+                error!("Failed to list blobs: {}", e);
+                return;
+            }
+        };
+        for names in names.chunks(SWEEP_BATCH) {
+            let live = match self.blob_index.are_live(names) {
+                Ok(live) => live,
+                Err(e) => {
+                    error!("Failed to check blobs: {}", e);
+                    return;
+                }
+            };
+            for (name, _) in names.iter().zip(live).filter(|(_, live)| !live) {
+                if let Err(e) = self.blob_store.delete(name) {
+                    error!("Failed to delete expired blob: {}", e);
+                }
             }
-        }) {
-            Ok(BehaviorAfterReceiveMessage::SendToAnotherSender)
-        } else {
-            self.queue.push(message)?;
-            Ok(BehaviorAfterReceiveMessage::PushedToQueue)
         }
     }
 
-    pub fn push_message_to_queue(&mut self, message: Message) -> Result<(),String> {
-        self.queue.push(message)?
+    /// Let the devices of an offline recipient know that a message is waiting.
+    fn wake(&self, recipient: &str) {
+        let wake_ups = match &self.wake_ups {
+            Some(wake_ups) => wake_ups,
+            None => return,
+        };
+        match self.push_endpoints.endpoints(recipient) {
+            Ok(endpoints) => endpoints.into_iter().for_each(|endpoint| wake_ups.wake(endpoint)),
+            Err(e) => error!("Failed to get push endpoints: {}", e),
+        }
     }
-} // impl Core
\ No newline at end of file
+
+    /// Have `device` woken up when a message is queued for `sender`.
+    pub fn register_push_endpoint(&self, sender: Sender, device: &str, endpoint: &str) -> Result<(), String> {
+        let wake_ups = match &self.wake_ups {
+            Some(wake_ups) => wake_ups,
+            None => return Err(NOTIFICATIONS_DISABLED.to_string()),
+        };
+        validate_device(device)?;
+        if !wake_ups.validate_endpoint(endpoint) {
+            return Err(ILLEGAL_INPUT.to_string());
+        }
+        let sender = sender_to_string(sender)?;
+        self.push_endpoints.register(&sender, device, endpoint)
+            .map_err(|e| Self::internal_error("register push endpoint", e))
+    }
+
+    pub fn unregister_push_endpoint(&self, sender: Sender, device: &str) -> Result<(), String> {
+        validate_device(device)?;
+        let sender = sender_to_string(sender)?;
+        self.push_endpoints.unregister(&sender, device)
+            .map_err(|e| Self::internal_error("unregister push endpoint", e))
+    }
+
+    /// Limits a client has to respect, as of the current config.
+    pub fn limits(&self) -> Limits {
+        let config = self.live_config.current();
+        Limits {
+            max_message_size: config.config.max_message_size,
+            max_group_members: config.config.max_group_members,
+            max_blob_size: config.blobs.max_blob_size,
+        }
+    }
+
+    /// Count the lines by occupancy for the metrics.
+    pub fn count_lines(&self) -> Result<(), String> {
+        let lines = self.line_manager.list_lines().map_err(|e| Self::internal_error("list lines", e))?;
+        let mut by_occupancy: HashMap<usize, i64> = HashMap::new();
+        for line_id in lines {
+            let senders = self.line_manager.get_senders(line_id).map_err(|e| Self::internal_error("get senders", e))?;
+            *by_occupancy.entry(senders.len()).or_default() += 1;
+        }
+        let gauge = &metrics().lines_by_occupancy;
+        gauge.reset();
+        for (occupancy, count) in by_occupancy {
+            gauge.with_label_values(&[self.queue.backend(), &occupancy.to_string()]).set(count);
+        }
+        Ok(())
+    }
+
+    /// Keep the index of lines of every online sender alive, see `LineManager::refresh_index`.
+    pub fn refresh_line_indexes(&self) -> Result<(), String> {
+        let senders: Vec<Sender> = self.online.lock().unwrap().keys().copied().collect();
+        for sender in senders {
+            let sender = sender_to_string(sender)?;
+            self.line_manager.refresh_index(&sender).map_err(|e| Self::internal_error("refresh line index", e))?;
+        }
+        Ok(())
+    }
+
+    /// Stop taking sessions and tell every online session to reconnect later.
+    /// Messages sent from now on are queued for their recipients.
+    pub fn start_draining(&self) {
+        let senders: Vec<Sender> = {
+            let online = self.online.lock().unwrap();
+            if self.draining.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            online.keys().copied().collect()
+        };
+        info!("Draining {} senders", senders.len());
+        for sender in senders {
+            self.disconnect_local(sender, DisconnectReason::ServerRestarting);
+        }
+        self.update_online_gauge();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Warn the sessions of lines that are about to expire, once per line.
+    pub fn warn_expiring_lines(&self) {
+        let auto_delete_time = match self.live_config.auto_delete_time().get() {
+            Some(time) => time,
+            None => {
+                self.warned_lines.lock().unwrap().clear();
+                return;
+            }
+        };
+        let window = EXPIRY_WARNING.min(auto_delete_time / 2);
+        let lines: HashSet<u16> = self.online.lock().unwrap().values().flatten().map(|s| s.line_id).collect();
+        self.warned_lines.lock().unwrap().retain(|line_id| lines.contains(line_id));
+        for line_id in lines {
+            match self.line_manager.ttl(line_id) {
+                Ok(Some(ttl)) if ttl <= window => {
+                    if !self.warned_lines.lock().unwrap().insert(line_id) {
+                        continue;
+                    }
+                    let warning = ServerMessage::LineExpiringSoon { line_id, expires_in: ttl };
+                    let online = self.online.lock().unwrap();
+                    for session in online.values().flatten().filter(|s| s.line_id == line_id) {
+                        session.recipient.do_send(warning.clone());
+                    }
+                }
+                // Using the line pushed its expiry back.
+                Ok(_) => {
+                    self.warned_lines.lock().unwrap().remove(&line_id);
+                }
+                Err(e) => error!("Failed to get line TTL: {}", e),
+            }
+        }
+    }
+} // impl Core
+
+/// Random, so an upload can't be guessed even though it is also bound to its uploader.
+fn new_upload_id() -> String {
+    let mut id = [0u8; blob::UPLOAD_ID_LEN / 2];
+    OsRng.fill_bytes(&mut id);
+    to_hex(&id)
+}
+
+/// Refresh the index of lines of online senders every hour.
+pub fn watch_line_indexes(core: SharedCore) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(INDEX_REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let core = core.clone();
+            match web::block(move || core.refresh_line_indexes()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to refresh line indexes: {}", e),
+                Err(e) => error!("Failed to run refresh line indexes: {}", e),
+            }
+        }
+    });
+}
+
+/// Remove expired blob content every minute.
+pub fn watch_blob_retention(core: SharedCore) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let core = core.clone();
+            if let Err(e) = web::block(move || core.sweep_blobs()).await {
+                error!("Failed to run sweep blobs: {}", e);
+            }
+        }
+    });
+}
+
+/// Count the lines for the metrics every minute, so scrapes never reach storage.
+pub fn watch_line_count(core: SharedCore) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(LINE_COUNT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let core = core.clone();
+            // Stale gauges are better than no metrics at all.
+            match web::block(move || core.count_lines()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to count lines: {}", e),
+                Err(e) => error!("Failed to run count lines: {}", e),
+            }
+        }
+    });
+}
+
+/// Time out unanswered calls.
+pub fn watch_call_timeouts(core: SharedCore) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(CALL_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let core = core.clone();
+            if let Err(e) = web::block(move || core.expire_calls()).await {
+                error!("Failed to run expire calls: {}", e);
+            }
+        }
+    });
+}
+
+/// Check the lines of online sessions for upcoming expiry every minute.
+pub fn watch_line_expiry(core: SharedCore) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let core = core.clone();
+            if let Err(e) = web::block(move || core.warn_expiring_lines()).await {
+                error!("Failed to run warn expiring lines: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{Actor, Context, Handler};
+    use super::super::load_config::load_config;
+
+    #[test]
+    fn device_names() {
+        assert!(validate_device(DEFAULT_DEVICE).is_ok());
+        assert!(validate_device("laptop-2_work").is_ok());
+        assert!(validate_device("").is_err());
+        assert!(validate_device("phone:1").is_err());
+        assert!(validate_device(&"a".repeat(MAX_DEVICE_LEN + 1)).is_err());
+    }
+
+    /// A session that keeps whatever it is sent.
+    struct Inbox(Arc<Mutex<Vec<ServerMessage>>>);
+
+    impl Actor for Inbox {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ServerMessage> for Inbox {
+        type Result = ();
+
+        fn handle(&mut self, msg: ServerMessage, _: &mut Self::Context) {
+            self.0.lock().unwrap().push(msg);
+        }
+    }
+
+    /// A sender no other run of the tests uses.
+    fn sender(name: &str) -> Sender {
+        string_to_sender(format!("{:-<64}", format!("{}-{}-", name, std::process::id()))).unwrap()
+    }
+
+    /// These need a Redis server: `REDIS_URL=redis://127.0.0.1/ cargo test -- --ignored`.
+    #[actix_web::test]
+    #[ignore = "needs a Redis server"]
+    async fn the_others_are_told_when_a_member_leaves() {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        let core = Core::new(load_config(Arc::new(LiveConfig::fixed(&url))).unwrap());
+        const LINE: u16 = 65_002;
+        let (alice, bob) = (sender("alice"), sender("bob"));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let alice_session = Inbox(received.clone()).start().recipient();
+        let bob_session = Inbox(Arc::new(Mutex::new(Vec::new()))).start().recipient();
+        core.join_line(alice, DEFAULT_DEVICE, LINE, Some(3), None, alice_session).unwrap();
+        core.join_line(bob, DEFAULT_DEVICE, LINE, None, None, bob_session).unwrap();
+
+        core.exit_line(bob, LINE).unwrap();
+        rt::time::sleep(Duration::from_millis(50)).await;
+        let bob_label = core.line_manager.member_label(LINE, &sender_to_string(bob).unwrap());
+        let told = received.lock().unwrap().iter()
+            .filter(|msg| matches!(msg, ServerMessage::PeerLeft { line_id: LINE, peer } if *peer == bob_label))
+            .count();
+        assert_eq!(told, 1);
+        assert!(!core.is_online(bob));
+        assert_eq!(core.exit_line(bob, LINE), Err(SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN.to_string()));
+        core.exit_line(alice, LINE).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use actix_web::rt;
+use tracing::{info, warn, error};
+
+use super::load_config::validate_config;
+use super::parse_config::{parse_config, config_modified_time, Config};
+use super::logging::apply_log_config;
+
+const FAILED_TO_LOAD_CONFIG: &str = "Failed to load config.";
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A validated config together with what was derived from it, swapped as one.
+struct Snapshot {
+    config: Arc<Config>,
+    auto_delete_time: Option<u64>,
+}
+
+/// Handle of the auto delete time of the running config, which storage reads on every write.
+#[derive(Clone)]
+pub struct AutoDeleteTime(Arc<RwLock<Arc<Snapshot>>>);
+
+impl AutoDeleteTime {
+    pub fn get(&self) -> Option<u64> {
+        self.0.read().unwrap().auto_delete_time
+    }
+
+    /// Storage that is tested on its own needs no config file.
+    #[cfg(test)]
+    pub fn fixed(auto_delete_time: Option<u64>) -> Self {
+        AutoDeleteTime(test_snapshot("", auto_delete_time))
+    }
+}
+
+/// The minimal config, with the database at `url`.
+#[cfg(test)]
+fn test_snapshot(url: &str, auto_delete_time: Option<u64>) -> Arc<RwLock<Arc<Snapshot>>> {
+    let config = serde_json::from_value(serde_json::json!({
+        "profile": {"Server Name": "", "Server Description": "", "Admin Contact": "", "Server Location": ""},
+        "database": {"Type": "redis", "url": url},
+        "config": {"Auto Delete": false, "Auto Delete Time": ""},
+    })).expect("minimal config");
+    Arc::new(RwLock::new(Arc::new(Snapshot { config: Arc::new(config), auto_delete_time })))
+}
+
+/// The running configuration. Readers always see a complete config,
+/// a reload swaps the whole thing or nothing.
+///
+/// Profile, auto delete time, log level and every other setting read through `current()`
+/// apply live. The server has no rate limits yet, so there are none to reload.
+pub struct LiveConfig {
+    snapshot: Arc<RwLock<Arc<Snapshot>>>,
+}
+
+impl LiveConfig {
+    pub fn load() -> Result<Self, (String, String)> {
+        let config = parse_config().map_err(|e| (FAILED_TO_LOAD_CONFIG.to_string(), e))?;
+        let auto_delete_time = validate_config(&config)?;
+        Ok(Self {
+            snapshot: Arc::new(RwLock::new(Arc::new(Snapshot { config: Arc::new(config), auto_delete_time }))),
+        })
+    }
+
+    /// The whole server tested without a config file, against the Redis at `url`.
+    #[cfg(test)]
+    pub fn fixed(url: &str) -> Self {
+        Self { snapshot: test_snapshot(url, None) }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.snapshot.read().unwrap().config.clone()
+    }
+
+    pub fn auto_delete_time(&self) -> AutoDeleteTime {
+        AutoDeleteTime(self.snapshot.clone())
+    }
+
+    /// Re-read the config file. An invalid config is rejected and the old one is kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let new_config = parse_config()?;
+        let auto_delete_time = validate_config(&new_config).map_err(|(e, detail)| format!("{} {}", e, detail))?;
+
+        let old_config = self.current();
+        if old_config.database.type_ != new_config.database.type_ || old_config.database.url != new_config.database.url {
+            warn!("Database settings changed, they will take effect after restarting the server");
+        }
+        if old_config.blobs.storage != new_config.blobs.storage || old_config.blobs.directory != new_config.blobs.directory {
+            warn!("Blob storage changed, it will take effect after restarting the server");
+        }
+        if old_config.notifications != new_config.notifications {
+            warn!("Notification settings changed, they will take effect after restarting the server");
+        }
+        if old_config.at_rest != new_config.at_rest {
+            warn!("At-rest key changed, it will take effect after restarting the server");
+        }
+        if old_config.cluster != new_config.cluster {
+            warn!("Cluster settings changed, they will take effect after restarting the server");
+        }
+        if old_config.log.format != new_config.log.format {
+            warn!("Log format changed, it will take effect after restarting the server");
+        }
+        apply_log_config(&new_config.log)?;
+
+        *self.snapshot.write().unwrap() = Arc::new(Snapshot { config: Arc::new(new_config), auto_delete_time });
+        info!("Config reloaded");
+        Ok(())
+    }
+} // impl LiveConfig
+
+/// Reload the config when the file changes, or when the process receives SIGHUP.
+pub fn watch_config(live_config: Arc<LiveConfig>) {
+    let polling = live_config.clone();
+    rt::spawn(async move {
+        let mut last_modified: Option<SystemTime> = config_modified_time().ok();
+        let mut interval = rt::time::interval(RELOAD_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let modified = match config_modified_time() {
+                Ok(modified) => Some(modified),
+                Err(e) => {
+                    warn!("Failed to check config file: {}", e);
+                    continue;
+                }
+            };
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            if let Err(e) = polling.reload() {
+                error!("Rejected new config, keep using the old one: {}", e);
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    rt::spawn(async move {
+        use rt::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading config");
+            if let Err(e) = live_config.reload() {
+                error!("Rejected new config, keep using the old one: {}", e);
+            }
+        }
+    });
+}
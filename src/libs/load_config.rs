@@ -1,19 +1,120 @@
 use super::redis_connect::{RedisConfig, RedisConnection};
 use super::message:: {
+    Message,
     line_manage::LineManager,
     redis_queue::RedisQueue,
-    queue_trait::MessageQueueStore
+    queue_trait::{MessageQueueStore, QueuedMessages}
 };
-use super::parse_config::{time_str_to_seconds, parse_config, Config, Profile};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+use super::parse_config::{time_str_to_seconds, Config};
+use super::live_config::LiveConfig;
+use super::metrics::{observe_storage, REDIS_BACKEND};
+use super::logging::validate_log_config;
+use super::cluster::{Cluster, ClusterListener};
+use super::notifier::{EndpointPolicy, PushEndpoints, WakeUps, WebhookNotifier};
+use super::at_rest::{AtRest, validate_at_rest_config};
+use super::blob::{BlobStore, FILESYSTEM_STORAGE, REDIS_STORAGE, fs_store::FsBlobStore, redis_store::RedisBlobStore, index::BlobIndex};
 
 const CONFIG_NOT_VALID: &str = "Config is not valid.";
-const FAILED_TO_LOAD_CONFIG: &str = "Failed to load config.";
 const FAILED_TO_CONNECT_TO_DATABASE: &str = "Failed to connect to database.";
 
 pub enum Queue{
     Redis(RedisQueue)
 }
 
+impl Queue {
+    /// Name of the backend, used to label metrics.
+    pub fn backend(&self) -> &'static str {
+        match self {
+            Queue::Redis(_) => REDIS_BACKEND,
+        }
+    }
+
+    pub fn push(&self, queue_owner: &str, message: Message) -> Result<u64, String> {
+        observe_storage(self.backend(), "push_message", || match self {
+            Queue::Redis(q) => q.push_message(queue_owner, message),
+        })
+    }
+
+    pub fn replay(&self, line_id: u16, sender: &str, device: &str, have_up_to: Option<u64>) -> Result<Vec<Message>, String> {
+        observe_storage(self.backend(), "replay", || match self {
+            Queue::Redis(q) => q.replay(line_id, sender, device, have_up_to),
+        })
+    }
+
+    pub fn fetch(&self, line_id: u16, sender: &str, cursor: u64, limit: usize) -> Result<QueuedMessages, String> {
+        observe_storage(self.backend(), "fetch", || match self {
+            Queue::Redis(q) => q.fetch(line_id, sender, cursor, limit),
+        })
+    }
+
+    pub fn device_cursor(&self, line_id: u16, sender: &str, device: &str) -> Result<u64, String> {
+        observe_storage(self.backend(), "device_cursor", || match self {
+            Queue::Redis(q) => q.device_cursor(line_id, sender, device),
+        })
+    }
+
+    pub fn ack(&self, line_id: u16, sender: &str, device: &str, cursor: u64) -> Result<(), String> {
+        observe_storage(self.backend(), "ack", || match self {
+            Queue::Redis(q) => q.ack(line_id, sender, device, cursor),
+        })
+    }
+
+    pub fn depth(&self, line_id: u16, sender: &str) -> Result<u64, String> {
+        observe_storage(self.backend(), "depth", || match self {
+            Queue::Redis(q) => q.depth(line_id, sender),
+        })
+    }
+
+    pub fn unacked(&self, line_id: u16, sender: &str, device: &str) -> Result<u64, String> {
+        observe_storage(self.backend(), "unacked", || match self {
+            Queue::Redis(q) => q.unacked(line_id, sender, device),
+        })
+    }
+
+    pub fn ttl(&self, line_id: u16, sender: &str) -> Result<Option<u64>, String> {
+        observe_storage(self.backend(), "ttl", || match self {
+            Queue::Redis(q) => q.ttl(line_id, sender),
+        })
+    }
+
+    pub fn ping(&self) -> Result<(), String> {
+        observe_storage(self.backend(), "ping", || match self {
+            Queue::Redis(q) => q.ping(),
+        })
+    }
+
+    pub fn push_event(&self, line_id: u16, recipient: &str, event: String) -> Result<(), String> {
+        observe_storage(self.backend(), "push_event", || match self {
+            Queue::Redis(q) => q.push_event(line_id, recipient, event),
+        })
+    }
+
+    pub fn pop_events(&self, line_id: u16, recipient: &str) -> Result<Vec<String>, String> {
+        observe_storage(self.backend(), "pop_events", || match self {
+            Queue::Redis(q) => q.pop_events(line_id, recipient),
+        })
+    }
+
+    pub fn purge(&self, line_id: u16, sender: &str) -> Result<(), String> {
+        observe_storage(self.backend(), "purge", || match self {
+            Queue::Redis(q) => q.purge(line_id, sender),
+        })
+    }
+}
+
+/// The readiness probe's own handle to storage, so it never waits on `Core`.
+pub struct StorageProbe(Queue);
+
+impl StorageProbe {
+    /// Whether the storage backend can serve requests.
+    pub fn ping(&self) -> Result<(), String> {
+        self.0.ping()
+    }
+}
+
 #[derive(PartialEq)]
 enum DatabaseType {
     Redis,
@@ -24,20 +125,29 @@ enum DatabaseType {
 pub struct LoadResult {
     pub queue: Queue,
     pub line_manager: LineManager,
-    pub profile: Profile,
+    pub live_config: Arc<LiveConfig>,
+    pub cluster: Option<Cluster>,
+    pub blob_store: BlobStore,
+    pub blob_index: BlobIndex,
+    pub push_endpoints: PushEndpoints,
+    /// Present when notifications are enabled.
+    pub wake_ups: Option<WakeUps>,
+    /// Has to be spawned once the `Core` it delivers to exists.
+    pub cluster_listener: Option<ClusterListener>,
+    /// Taken by the readiness probe.
+    pub storage_probe: Option<StorageProbe>,
 }
 
-pub fn load_config() -> Result<LoadResult, (String, String)> {
-    let config: Config = match parse_config() {
-        Ok(config) => config,
-        Err(e) => return Err((FAILED_TO_LOAD_CONFIG.to_string(), e.to_string())),
-    };
-    let Config { profile, database, config } = config;
+/// Check a parsed config, returning the auto delete time in seconds.
+pub fn validate_config(config: &Config) -> Result<Option<u64>, (String, String)> {
+    let Config { database, config, log, cluster, blobs, notifications, at_rest, .. } = config;
+
+    validate_log_config(log).map_err(|e| (CONFIG_NOT_VALID.to_string(), format!("Log level is not valid: {}", e)))?;
 
     // Get database type
     let database_type = if database.type_ == "redis" {
         DatabaseType::Redis
-    } else if database.type_ == "" {
+    } else if database.type_.is_empty() {
         DatabaseType::None
     } else {
         DatabaseType::Unknown
@@ -56,15 +166,55 @@ pub fn load_config() -> Result<LoadResult, (String, String)> {
             Some(time) => time,
             None => return Err((CONFIG_NOT_VALID.to_string(), "Auto delete time is not valid.".to_string())),
         };
+        // A zero TTL would delete every key as soon as it is written.
+        if time == 0 {
+            return Err((CONFIG_NOT_VALID.to_string(), "Auto delete time must be longer than zero.".to_string()));
+        }
         Some(time)
     } else {
         None
     };
+    if config.max_group_members < 2 {
+        return Err((CONFIG_NOT_VALID.to_string(), "Max group members must be at least 2.".to_string()));
+    }
+    if time_str_to_seconds(&config.shutdown_timeout).is_none() {
+        return Err((CONFIG_NOT_VALID.to_string(), "Shutdown timeout is not valid.".to_string()));
+    }
+    if !matches!(time_str_to_seconds(&cluster.session_lease), Some(lease) if lease > 0) {
+        return Err((CONFIG_NOT_VALID.to_string(), "Session lease is not valid.".to_string()));
+    }
+    if time_str_to_seconds(&notifications.min_interval).is_none() {
+        return Err((CONFIG_NOT_VALID.to_string(), "Notification min interval is not valid.".to_string()));
+    }
+    if time_str_to_seconds(&notifications.coalesce_delay).is_none() {
+        return Err((CONFIG_NOT_VALID.to_string(), "Notification coalesce delay is not valid.".to_string()));
+    }
+    if blobs.storage != REDIS_STORAGE && blobs.storage != FILESYSTEM_STORAGE {
+        return Err((CONFIG_NOT_VALID.to_string(), "Blob storage is not supported.".to_string()));
+    }
+    if blobs.max_blob_size == 0 {
+        return Err((CONFIG_NOT_VALID.to_string(), "Max blob size must be larger than zero.".to_string()));
+    }
+    validate_at_rest_config(at_rest).map_err(|e| (CONFIG_NOT_VALID.to_string(), e))?;
+    Ok(auto_delete_time)
+}
+
+pub fn load_config(live_config: Arc<LiveConfig>) -> Result<LoadResult, (String, String)> {
+    let config = live_config.current();
+
+    let at_rest = match AtRest::load(&config.at_rest) {
+        Ok(at_rest) => at_rest,
+        Err(e) => return Err(("Failed to load the at-rest key.".to_string(), e)),
+    };
+    if at_rest.is_enabled() {
+        info!("At-rest protection enabled, sender names in storage are pseudonymized");
+    }
 
     // connect to database
     let redis_connection = match RedisConnection::new(&RedisConfig {
-        url: database.url,
-        auto_delete_time,
+        url: config.database.url.clone(),
+        auto_delete_time: live_config.auto_delete_time(),
+        at_rest,
     }) {
         Ok(connection) => connection,
         Err(e) => return Err((FAILED_TO_CONNECT_TO_DATABASE.to_string(), e.to_string())),
@@ -75,6 +225,46 @@ pub fn load_config() -> Result<LoadResult, (String, String)> {
         Ok(queue) => Queue::Redis(queue),
         Err(e) => return Err((FAILED_TO_CONNECT_TO_DATABASE.to_string(), e.to_string())),
     };
+    let storage_probe = match RedisQueue::new(&redis_connection) {
+        Ok(queue) => StorageProbe(Queue::Redis(queue)),
+        Err(e) => return Err((FAILED_TO_CONNECT_TO_DATABASE.to_string(), e.to_string())),
+    };
+
+    // join the cluster
+    let (cluster, cluster_listener) = if config.cluster.enabled {
+        // Already validated, see `validate_config`.
+        let lease = time_str_to_seconds(&config.cluster.session_lease).unwrap_or(30);
+        let (cluster, listener) = Cluster::new(&redis_connection, lease);
+        (Some(cluster), Some(listener))
+    } else {
+        (None, None)
+    };
+
+    // open blob storage
+    let blob_store = if config.blobs.storage == FILESYSTEM_STORAGE {
+        match FsBlobStore::new(&config.blobs.directory) {
+            Ok(store) => BlobStore::Filesystem(store),
+            Err(e) => return Err(("Failed to open blob directory.".to_string(), e)),
+        }
+    } else {
+        BlobStore::Redis(RedisBlobStore::new(&redis_connection))
+    };
+    let blob_index = BlobIndex::new(&redis_connection);
+
+    // start sending wake-ups
+    let wake_ups = if config.notifications.enabled {
+        // Already validated, see `validate_config`.
+        let min_interval = time_str_to_seconds(&config.notifications.min_interval).unwrap_or(30);
+        let coalesce_delay = time_str_to_seconds(&config.notifications.coalesce_delay).unwrap_or(2);
+        let policy = EndpointPolicy::new(&config.notifications.allowed_hosts);
+        match WakeUps::spawn(WebhookNotifier::new(policy.clone()), policy, Duration::from_secs(min_interval), Duration::from_secs(coalesce_delay)) {
+            Ok(wake_ups) => Some(wake_ups),
+            Err(e) => return Err(("Failed to start the notifier.".to_string(), e)),
+        }
+    } else {
+        None
+    };
+    let push_endpoints = PushEndpoints::new(&redis_connection);
 
     // create line manager
     let line_manager = match LineManager::new(redis_connection) {
@@ -85,13 +275,13 @@ pub fn load_config() -> Result<LoadResult, (String, String)> {
     Ok(LoadResult {
         queue,
         line_manager,
-        profile,
+        live_config,
+        cluster,
+        blob_store,
+        blob_index,
+        push_endpoints,
+        wake_ups,
+        cluster_listener,
+        storage_probe: Some(storage_probe),
     })
 }
-
-pub fn load_profile() -> Result<Profile,String> {
-    match parse_config() {
-        Ok(config) => Ok(config.profile),
-        Err(e) => Err(e.to_string()),
-    }
-}
\ No newline at end of file
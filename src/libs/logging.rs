@@ -0,0 +1,146 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::hash::BuildHasher;
+use std::sync::{OnceLock, RwLock};
+use serde_derive::{Deserialize, Serialize};
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+use tracing_subscriber::prelude::*;
+
+const DEFAULT_LEVEL: &str = "info";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+/// How sender and line identifiers appear in logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+    /// Log identifiers as they are.
+    None,
+    /// Keep only a short prefix.
+    Truncate,
+    /// Replace with a keyed hash. The key is random per process,
+    /// so the same identifier can be followed within a run but not across restarts.
+    Hash,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogConfig {
+    #[serde(rename = "Format", default = "default_format")]
+    pub format: LogFormat,
+    /// Filter directives such as `info` or `paper_cup_phone=debug,warn`.
+    /// `RUST_LOG` takes precedence when it is set.
+    #[serde(rename = "Level", default = "default_level")]
+    pub level: String,
+    #[serde(rename = "Redaction", default = "default_redaction")]
+    pub redaction: Redaction,
+}
+
+fn default_format() -> LogFormat {
+    LogFormat::Pretty
+}
+
+fn default_level() -> String {
+    DEFAULT_LEVEL.to_string()
+}
+
+fn default_redaction() -> Redaction {
+    Redaction::Hash
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: default_format(),
+            level: default_level(),
+            redaction: default_redaction(),
+        }
+    }
+}
+
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+static REDACTION: RwLock<Redaction> = RwLock::new(Redaction::Hash);
+
+fn redaction_key() -> &'static RandomState {
+    static KEY: OnceLock<RandomState> = OnceLock::new();
+    KEY.get_or_init(RandomState::new)
+}
+
+fn env_filter(level: &str) -> Result<EnvFilter, String> {
+    match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(env) if !env.is_empty() => EnvFilter::try_new(env),
+        _ => EnvFilter::try_new(level),
+    }.map_err(|e| e.to_string())
+}
+
+pub fn validate_log_config(config: &LogConfig) -> Result<(), String> {
+    EnvFilter::try_new(&config.level).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Install the global subscriber. Only the format cannot be changed later.
+pub fn init_logging(config: &LogConfig) -> Result<(), String> {
+    let (filter, handle) = reload::Layer::new(env_filter(&config.level)?);
+    let (json, pretty) = match config.format {
+        LogFormat::Json => (Some(fmt::layer().json()), None),
+        LogFormat::Pretty => (None, Some(fmt::layer().pretty())),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(pretty)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+    let _ = FILTER_HANDLE.set(handle);
+    *REDACTION.write().unwrap() = config.redaction;
+    Ok(())
+}
+
+/// Apply the level and redaction of a reloaded config.
+pub fn apply_log_config(config: &LogConfig) -> Result<(), String> {
+    if let Some(handle) = FILTER_HANDLE.get() {
+        handle.reload(env_filter(&config.level)?).map_err(|e| e.to_string())?;
+    }
+    *REDACTION.write().unwrap() = config.redaction;
+    Ok(())
+}
+
+/// An identifier as it may be written to the logs.
+pub fn redact(id: impl Display) -> String {
+    let id = id.to_string();
+    match *REDACTION.read().unwrap() {
+        Redaction::None => id,
+        Redaction::Truncate => {
+            let keep = (id.chars().count() / 4).min(8);
+            format!("{}…", id.chars().take(keep).collect::<String>())
+        }
+        Redaction::Hash => format!("#{:016x}", redaction_key().hash_one(&id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redaction_modes() {
+        let sender = "A".repeat(64);
+
+        *REDACTION.write().unwrap() = Redaction::None;
+        assert_eq!(redact(&sender), sender);
+        assert_eq!(redact(65535), "65535");
+
+        *REDACTION.write().unwrap() = Redaction::Truncate;
+        assert_eq!(redact(&sender), format!("{}…", "A".repeat(8)));
+        assert_eq!(redact(65535), "6…");
+
+        *REDACTION.write().unwrap() = Redaction::Hash;
+        let hashed = redact(&sender);
+        assert!(!hashed.contains("AAAA"));
+        assert_eq!(hashed, redact(&sender));
+        assert_ne!(hashed, redact("B".repeat(64)));
+    }
+}
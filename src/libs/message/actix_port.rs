@@ -1,6 +1,7 @@
 use actix::prelude::*;
 use serde_derive::{Deserialize, Serialize};
 
+#[allow(dead_code)]
 #[derive(Message, Serialize, Deserialize, Debug, Clone)]
 #[rtype(result = "()")]
 pub struct ChatMessage {
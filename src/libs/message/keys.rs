@@ -0,0 +1,95 @@
+//! Names of the keys lines, queues and blobs are stored under.
+
+/// Matches the key of every line.
+pub const LINE_KEY_PATTERN: &str = "sender:*:line";
+
+pub fn line_key(line_id: u16) -> String {
+    format!("sender:{}:line", line_id)
+}
+
+/// Lines the sender has joined, the reverse of `line_key`.
+pub fn sender_lines_key(sender: &str) -> String {
+    format!("sender:{}:lines", sender)
+}
+
+/// Admin and member cap of a group line, absent for a line of two.
+pub fn group_key(line_id: u16) -> String {
+    format!("sender:{}:group", line_id)
+}
+
+/// Senders kicked from a group line, who may not join it again until invited.
+pub fn banned_key(line_id: u16) -> String {
+    format!("sender:{}:banned", line_id)
+}
+
+pub fn line_id_from_key(key: &str) -> Option<u16> {
+    key.strip_prefix("sender:")?.strip_suffix(":line")?.parse().ok()
+}
+
+pub fn queue_key(line_id: u16, sender: &str) -> String {
+    format!("line:{}:{}", line_id, sender)
+}
+
+/// Sequence number of the first message still in the queue.
+pub fn queue_base_key(line_id: u16, sender: &str) -> String {
+    format!("line:{}:{}:base", line_id, sender)
+}
+
+/// Server events kept for a recipient who was offline when they happened.
+pub fn event_queue_key(line_id: u16, sender: &str) -> String {
+    format!("line:{}:{}:events", line_id, sender)
+}
+
+/// Sequence number up to which each device of the recipient has received the queue.
+pub fn queue_cursors_key(line_id: u16, sender: &str) -> String {
+    format!("line:{}:{}:cursors", line_id, sender)
+}
+
+/// When each device of the recipient last acknowledged the queue.
+pub fn queue_seen_key(line_id: u16, sender: &str) -> String {
+    format!("line:{}:{}:seen", line_id, sender)
+}
+
+/// Owner of the queue of sealed messages for `recipient`, kept apart from its own queue.
+pub fn sealed_queue_owner(recipient: &str) -> String {
+    format!("sealed:{}", recipient)
+}
+
+/// Instances holding a session of the sender, scored by when their lease ends.
+pub fn presence_key(sender: &str) -> String {
+    format!("presence:{}", sender)
+}
+
+/// Channel the instance holding a session of the sender listens on.
+pub fn delivery_channel(sender: &str) -> String {
+    format!("deliver:{}", sender)
+}
+
+pub fn sender_from_channel(channel: &str) -> Option<&str> {
+    channel.strip_prefix("deliver:")
+}
+
+/// Uploader and size of a finished blob.
+pub fn blob_key(line_id: u16, blob_id: &str) -> String {
+    format!("blob:{}:{}", line_id, blob_id)
+}
+
+/// Members of the line who have fetched the blob.
+pub fn blob_fetched_key(line_id: u16, blob_id: &str) -> String {
+    format!("blob:{}:{}:fetched", line_id, blob_id)
+}
+
+/// Content of a blob, when blobs are stored in Redis.
+pub fn blob_content_key(name: &str) -> String {
+    format!("blob:content:{}", name)
+}
+
+/// Line, uploader and size so far of an unfinished upload.
+pub fn upload_key(upload_id: &str) -> String {
+    format!("upload:{}", upload_id)
+}
+
+/// Wake-up endpoint of each device of the sender, see `notifier`.
+pub fn push_endpoints_key(sender: &str) -> String {
+    format!("push:{}", sender)
+}
@@ -1,128 +1,417 @@
-use std::sync::{Arc, Mutex};
-use redis::{Commands, Client, RedisResult};
-use crate::libs::redis_connect::RedisConnection;
+use redis::Commands;
+use crate::libs::redis_connect::{RedisConnection, RedisPool, PooledConnection, AutoDeleteTime, apply_key_expiry, key_ttl};
+use super::keys::{line_key, line_id_from_key, group_key, banned_key, sender_lines_key, LINE_KEY_PATTERN};
+use super::sender_label;
+use super::retention::{key_expiry, KeyExpiry};
+use crate::libs::metrics::{observe_storage, REDIS_BACKEND};
+use crate::libs::at_rest::AtRest;
 
 pub struct LineManager {
-    client: Arc<Mutex<Client>>,
-    auto_delete_time: Option<u64>
-}
-
-fn set_ttl_for_key(con: &mut redis::Connection, key: &String, time: u64) -> Result<bool, String> {
-    match con.expire::<&String, usize>(&key, time as usize) {
-        Ok(_) => Ok(true),
-        Err(e) => Err(e.to_string()),
-    }
+    client: RedisPool,
+    auto_delete_time: AutoDeleteTime,
+    at_rest: AtRest,
 }
 
 pub enum AddSenderActuallyDone {
     AddTheFirstSender,
+    OpenTheGroup,               // groups only
     AddTheSecondSender,
-    TryToAddTheThirdSender,     // failed
+    AddAnotherMember,           // groups only
+    LineIsFull,                 // failed
+    Banned,                     // failed
     AlreadyInLine,
 }
 
+/// A line of two is the default, a group line is created on purpose by its admin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub admin: String,
+    pub max_members: usize,
+}
+
+pub const LINE_MEMBERS: usize = 2;
+
 const TRY_TO_REMOVE_A_SENDER_NOT_EXIST: &str = "Try to remove a sender that not exist.";
 
 impl LineManager {
+    /// Every write to a line counts as using it, see `retention`.
+    /// The group record and bans of a line live as long as its membership.
+    /// The index of lines of a member is kept alive on its own, see `refresh_index`.
+    fn apply_expiry(&self, con: &mut PooledConnection, line_id: u16) -> Result<(), String> {
+        let auto_delete_time = self.auto_delete_time.get();
+        for key in [line_key(line_id), group_key(line_id), banned_key(line_id)] {
+            let exists: bool = con.exists(&key).map_err(|e| e.to_string())?;
+            if exists {
+                apply_key_expiry(con, &key, key_expiry(auto_delete_time))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn lines_key(&self, sender: &str) -> String {
+        sender_lines_key(&self.at_rest.sender_name(sender))
+    }
+
+    /// Line ids joined by ':', encrypted like membership.
+    fn read_lines(&self, con: &mut PooledConnection, sender: &str) -> Result<Vec<u16>, String> {
+        let key = self.lines_key(sender);
+        let value: Option<String> = con.get(&key).map_err(|e| e.to_string())?;
+        match value {
+            Some(value) => Ok(self.at_rest.open(&key, &value)?.split(':').filter_map(|line| line.parse().ok()).collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Watch what a change of membership of `sender` reads, so `commit_members` fails
+    /// when anything else changed it in the meantime.
+    fn watch_members(&self, con: &mut PooledConnection, line_id: u16, sender: &str) -> Result<(), String> {
+        redis::cmd("WATCH")
+            .arg(line_key(line_id))
+            .arg(group_key(line_id))
+            .arg(self.lines_key(sender))
+            .query(con)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Run `f`, which watches keys, then stop watching them, since the connection is reused.
+    fn watching<T>(con: &mut PooledConnection, f: impl FnOnce(&mut PooledConnection) -> Result<T, String>) -> Result<T, String> {
+        let result = f(con);
+        // Failing to unwatch breaks the connection, which is then not reused.
+        let _: redis::RedisResult<()> = redis::cmd("UNWATCH").query(con);
+        result
+    }
+
+    /// Write the members of a line and the index of lines of `sender`, who just joined or left it,
+    /// in one transaction. Returns `false` when a watched key changed, and nothing was written.
+    /// A line nobody is left in is deleted, group and bans included, so it can be opened anew.
+    /// With `open_group`, the line becomes a group of up to that many members with `sender` as its admin.
+    fn commit_members(&self, con: &mut PooledConnection, line_id: u16, members: &str, sender: &str, joined: bool, open_group: Option<usize>) -> Result<bool, String> {
+        let mut lines = self.read_lines(con, sender)?;
+        lines.retain(|line| *line != line_id);
+        if joined {
+            lines.push(line_id);
+        }
+        let key = line_key(line_id);
+        let lines_key = self.lines_key(sender);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if members.is_empty() {
+            pipe.del(vec![key, group_key(line_id), banned_key(line_id)]).ignore();
+        } else {
+            pipe.set(&key, self.at_rest.seal(&key, members)?).ignore();
+        }
+        if let Some(max_members) = open_group {
+            let group_key = group_key(line_id);
+            pipe.hset_multiple(&group_key, &[
+                ("admin", self.at_rest.seal(&group_key, sender)?),
+                ("max_members", max_members.to_string()),
+            ]).ignore()
+                .del(banned_key(line_id)).ignore();
+        }
+        if lines.is_empty() {
+            pipe.del(&lines_key).ignore();
+        } else {
+            let lines: Vec<String> = lines.iter().map(u16::to_string).collect();
+            pipe.set(&lines_key, self.at_rest.seal(&lines_key, &lines.join(":"))?).ignore();
+            if let KeyExpiry::ExpireIn(time) = key_expiry(self.auto_delete_time.get()) {
+                pipe.expire(&lines_key, time as usize).ignore();
+            }
+        }
+        let committed: Option<()> = pipe.query(con).map_err(|e| e.to_string())?;
+        Ok(committed.is_some())
+    }
+
+    fn read_group(&self, con: &mut PooledConnection, line_id: u16) -> Result<Option<Group>, String> {
+        let key = group_key(line_id);
+        let (admin, max_members): (Option<String>, Option<usize>) = redis::cmd("HMGET")
+            .arg(&key)
+            .arg("admin")
+            .arg("max_members")
+            .query(con)
+            .map_err(|e| e.to_string())?;
+        match (admin, max_members) {
+            (Some(admin), Some(max_members)) => Ok(Some(Group { admin: self.at_rest.open(&key, &admin)?, max_members })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Members joined by ':', encrypted when at-rest protection is enabled.
+    fn read_members(&self, con: &mut PooledConnection, line_id: u16) -> Result<Option<String>, String> {
+        let key = line_key(line_id);
+        let value: Option<String> = con.get(&key).map_err(|e| e.to_string())?;
+        value.map(|value| self.at_rest.open(&key, &value)).transpose()
+    }
+
+    /// See `AtRest::member_label`.
+    pub fn member_label(&self, line_id: u16, sender: &str) -> String {
+        self.at_rest.member_label(line_id, sender)
+    }
+
+    /// See `message::sender_label`.
+    pub fn sender_label(&self, line_id: u16, sender: &str) -> String {
+        sender_label(&self.at_rest, line_id, sender)
+    }
+
     pub fn new(config: RedisConnection) -> Result<Self, String> {
         Ok(Self {
             client: config.get_client(),
-            auto_delete_time: config.auto_delete_time
+            auto_delete_time: config.auto_delete_time.clone(),
+            at_rest: config.at_rest.clone(),
         })
     }
-    pub fn add_sender(&self, sender: String, line_id: u16) -> Result<AddSenderActuallyDone, String> {
-        let key = format!("sender:{}:line", line_id);
-        let mut con = match self.client.lock().unwrap().get_connection() {
-            Ok(con) => con,
-            Err(e) => return Err(e.to_string()),
-        };
-
-        // Check if there's a record associated with the key.
-        let existing_value: Option<String> = con.get(&key).map_err(|e| e.to_string())?;
-
-        match existing_value {
-            None => {
-                // Add the new record.
-                match con.set::<&String, String, ()>(&key, sender) {
-                    Ok(_) => Ok(true),
-                    Err(e) => Err(e.to_string()),
-                }.expect("Failed to add sender to line.");
-                match self.auto_delete_time {
-                    Some(time) => {
-                        set_ttl_for_key(&mut con, &key, time)
-                    },
-                    None => Ok(true),
-                }.expect("Failed to set auto delete time.");
-                Ok(AddSenderActuallyDone::AddTheFirstSender)
-            }
-            Some(value) => {
-                // Check if sender is in the value.
-                let senders: Vec<&str> = value.split(':').collect();
-                if senders.contains(&sender.as_str()) {
-                    Ok(AddSenderActuallyDone::AlreadyInLine)
-                } else if senders.len() == 1 {
-                    let new_value = format!("{}:{}", value, sender);
-                    match con.set::<&String, &String, ()>(&key, &new_value) {
-                        Ok(_) => Ok(true),
-                        Err(e) => Err(e.to_string()),
-                    }.expect("Failed to add sender to line.");
-                    Ok(AddSenderActuallyDone::AddTheSecondSender)
-                } else {
-                    Ok(AddSenderActuallyDone::TryToAddTheThirdSender)
+    /// With `open_group`, an unused line is opened as a group with `sender` as its admin,
+    /// in the same transaction. Joining a line in use ignores it.
+    pub fn add_sender(&self, sender: String, line_id: u16, open_group: Option<usize>) -> Result<AddSenderActuallyDone, String> {
+        observe_storage(REDIS_BACKEND, "add_sender", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            // Start over whenever someone else joined, left or opened the group in between.
+            Self::watching(&mut con, |con| loop {
+                self.watch_members(con, line_id, &sender)?;
+
+                // Check if there's a record associated with the key.
+                // Lines left empty before they were deleted count as unused.
+                let existing_value = self.read_members(con, line_id)?.filter(|value| !value.is_empty());
+                let group = self.read_group(con, line_id)?;
+                let max_members = group.as_ref().map_or(LINE_MEMBERS, |group| group.max_members);
+                // Opening a group lifts the bans left behind.
+                let opening = open_group.filter(|_| existing_value.is_none() && group.is_none());
+                let banned: bool = con.sismember(banned_key(line_id), self.at_rest.member_label(line_id, &sender)).map_err(|e| e.to_string())?;
+                if banned && opening.is_none() {
+                    return Ok(AddSenderActuallyDone::Banned);
                 }
-            } // match existing_value -> Some(value)
-        } // match existing_value
+
+                let done = match existing_value {
+                    None => {
+                        // Add the new record.
+                        if !self.commit_members(con, line_id, &sender, &sender, true, opening)? {
+                            continue;
+                        }
+                        match opening {
+                            Some(_) => AddSenderActuallyDone::OpenTheGroup,
+                            None => AddSenderActuallyDone::AddTheFirstSender,
+                        }
+                    }
+                    Some(value) => {
+                        // Check if sender is in the value.
+                        let senders: Vec<&str> = value.split(':').collect();
+                        if senders.contains(&sender.as_str()) {
+                            // Lines joined before the index was kept are added on the next join.
+                            if self.read_lines(con, &sender)?.contains(&line_id) {
+                                apply_key_expiry(con, &self.lines_key(&sender), key_expiry(self.auto_delete_time.get()))?;
+                            } else if !self.commit_members(con, line_id, &value, &sender, true, None)? {
+                                continue;
+                            }
+                            AddSenderActuallyDone::AlreadyInLine
+                        } else if senders.len() < max_members {
+                            let new_value = format!("{}:{}", value, sender);
+                            if !self.commit_members(con, line_id, &new_value, &sender, true, None)? {
+                                continue;
+                            }
+                            match senders.len() {
+                                1 => AddSenderActuallyDone::AddTheSecondSender,
+                                _ => AddSenderActuallyDone::AddAnotherMember,
+                            }
+                        } else {
+                            return Ok(AddSenderActuallyDone::LineIsFull);
+                        }
+                    } // match existing_value -> Some(value)
+                }; // match existing_value
+                self.apply_expiry(con, line_id)?;
+                return Ok(done);
+            })
+        })
     } // fn add_sender
 
     pub fn refresh_ttl(&self, line_id: u16) -> Result<bool,String> {
-        let key = format!("sender:{}:line", line_id);
-        let mut con = match self.client.lock().unwrap().get_connection() {
-            Ok(con) => con,
-            Err(e) => return Err(e.to_string()),
-        };
-
-        match self.auto_delete_time {
-            Some(time) => {
-                set_ttl_for_key(&mut con, &key, time)
-            },
-            None => Ok(true),
-        }.expect("Failed to set auto delete time.");
-        Ok(true)
+        observe_storage(REDIS_BACKEND, "refresh_ttl", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            self.apply_expiry(&mut con, line_id)?;
+            Ok(true)
+        })
     } // fn refresh_ttl
 
     pub fn get_senders(&self, line_id: u16) -> Result<Vec<String>, String> {
-        let key = format!("sender:{}:line", line_id);
-        let mut con = match self.client.lock().unwrap().get_connection() {
-            Ok(con) => con,
-            Err(e) => return Err(e.to_string()),
-        };
-
-        let senders: Option<String> = con.get(&key).map_err(|e| e.to_string())?;
-        match senders {
-            Some(senders) => Ok(senders.split(':').map(|s| s.to_string()).collect()),
-            None => Ok(Vec::new()),
-        }
+        observe_storage(REDIS_BACKEND, "get_senders", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            let senders = self.read_members(&mut con, line_id)?;
+            match senders {
+                Some(senders) => Ok(senders.split(':').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()),
+                None => Ok(Vec::new()),
+            }
+        })
     } // fn get_senders
 
+    /// Every line that currently has a record.
+    pub fn list_lines(&self) -> Result<Vec<u16>, String> {
+        observe_storage(REDIS_BACKEND, "list_lines", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            let keys: Vec<String> = con.scan_match(LINE_KEY_PATTERN).map_err(|e| e.to_string())?.collect();
+            let mut lines: Vec<u16> = keys.iter().filter_map(|key| line_id_from_key(key)).collect();
+            lines.sort_unstable();
+            lines.dedup();
+            Ok(lines)
+        })
+    } // fn list_lines
+
+    /// Seconds until the line expires, `None` when it never does.
+    pub fn ttl(&self, line_id: u16) -> Result<Option<u64>, String> {
+        observe_storage(REDIS_BACKEND, "ttl", || {
+            let key = line_key(line_id);
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            key_ttl(&mut con, &key)
+        })
+    } // fn ttl
+
     pub fn remove_sender(&self, sender: String, line_id: u16) -> Result<(),String> {
-        let key = format!("sender:{}:line", line_id);
-        let mut con = match self.client.lock().unwrap().get_connection() {
-            Ok(con) => con,
-            Err(e) => return Err(e.to_string()),
-        };
-
-        let senders: Option<String> = con.get(&key).map_err(|e| e.to_string())?;
-        match senders {
-            Some(senders) => {
-                let new_senders: Vec<String> = senders.split(':').map(|s| s.to_string()).filter(|s| s != &sender).collect();
-                let new_value = new_senders.join(":");
-                match con.set::<&String, &String, ()>(&key, &new_value) {
-                    Ok(_) => Ok(true),
-                    Err(e) => Err(e.to_string()),
-                }.expect("Failed to remove sender from line.");
-                Ok(())
-            },
-            None => Err(TRY_TO_REMOVE_A_SENDER_NOT_EXIST.to_string()),
+        observe_storage(REDIS_BACKEND, "remove_sender", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            Self::watching(&mut con, |con| loop {
+                self.watch_members(con, line_id, &sender)?;
+                let senders = self.read_members(con, line_id)?;
+                match senders {
+                    Some(senders) => {
+                        let new_senders: Vec<String> = senders.split(':').filter(|s| !s.is_empty() && *s != sender).map(|s| s.to_string()).collect();
+                        let new_value = new_senders.join(":");
+                        if !self.commit_members(con, line_id, &new_value, &sender, false, None)? {
+                            continue;
+                        }
+                        return self.apply_expiry(con, line_id);
+                    },
+                    None => return Err(TRY_TO_REMOVE_A_SENDER_NOT_EXIST.to_string()),
+                }
+            })
+        })
+    }
+
+    /// Lines `sender` has joined, per the index kept with membership.
+    /// A line that expired may still be listed, the index outlives it.
+    pub fn lines_of(&self, sender: &str) -> Result<Vec<u16>, String> {
+        observe_storage(REDIS_BACKEND, "lines_of", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            self.read_lines(&mut con, sender)
+        })
+    } // fn lines_of
+
+    /// Keep the index of lines of `sender` as long as a line, counting from now.
+    /// Written on every join and leave, and refreshed while the sender is online.
+    pub fn refresh_index(&self, sender: &str) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "refresh_index", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            apply_key_expiry(&mut con, &self.lines_key(sender), key_expiry(self.auto_delete_time.get()))
+        })
+    } // fn refresh_index
+
+    /// `None` for a line of two.
+    pub fn group(&self, line_id: u16) -> Result<Option<Group>, String> {
+        observe_storage(REDIS_BACKEND, "group", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            self.read_group(&mut con, line_id)
+        })
+    } // fn group
+
+    /// Keep the member labeled `label` out of a group line, or let them join again.
+    /// Bans are kept by label, see `AtRest::member_label`, so they can be lifted by it.
+    pub fn set_banned(&self, line_id: u16, label: &str, banned: bool) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "set_banned", || {
+            let mut con = match self.client.get_connection() {
+                Ok(con) => con,
+                Err(e) => return Err(e.to_string()),
+            };
+
+            match banned {
+                true => con.sadd::<_, _, ()>(banned_key(line_id), label),
+                false => con.srem::<_, _, ()>(banned_key(line_id), label),
+            }.map_err(|e| e.to_string())?;
+            self.apply_expiry(&mut con, line_id)
+        })
+    } // fn set_banned
+}
+
+/// These need a Redis server: `REDIS_URL=redis://127.0.0.1/ cargo test -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::redis_connect::RedisConfig;
+
+    const LINE: u16 = 65_001;
+
+    fn line_manager() -> LineManager {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        LineManager::new(RedisConnection::new(&RedisConfig {
+            url,
+            auto_delete_time: AutoDeleteTime::fixed(None),
+            at_rest: AtRest::default(),
+        }).unwrap()).unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server"]
+    fn the_last_member_leaving_deletes_the_line() {
+        let lines = line_manager();
+        let (alice, bob) = (format!("alice-{}", std::process::id()), format!("bob-{}", std::process::id()));
+        assert!(matches!(lines.add_sender(alice.clone(), LINE, Some(3)).unwrap(), AddSenderActuallyDone::OpenTheGroup));
+        lines.remove_sender(alice, LINE).unwrap();
+
+        assert!(lines.get_senders(LINE).unwrap().is_empty());
+        assert_eq!(lines.group(LINE).unwrap(), None);
+        assert!(matches!(lines.add_sender(bob.clone(), LINE, None).unwrap(), AddSenderActuallyDone::AddTheFirstSender));
+        assert_eq!(lines.get_senders(LINE).unwrap(), [bob.as_str()]);
+        lines.remove_sender(bob, LINE).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server"]
+    fn only_one_of_two_concurrent_creators_opens_the_group() {
+        let admins: Vec<String> = (0..2).map(|i| format!("admin-{}-{}", i, std::process::id())).collect();
+        let created: Vec<bool> = std::thread::scope(|scope| {
+            let handles: Vec<_> = admins.iter()
+                .map(|admin| scope.spawn(|| line_manager().add_sender(admin.clone(), LINE + 1, Some(3)).unwrap()))
+                .collect();
+            handles.into_iter().map(|handle| matches!(handle.join().unwrap(), AddSenderActuallyDone::OpenTheGroup)).collect()
+        });
+        assert_eq!(created.iter().filter(|created| **created).count(), 1);
+
+        let lines = line_manager();
+        let admin = lines.group(LINE + 1).unwrap().unwrap().admin;
+        assert_eq!(lines.get_senders(LINE + 1).unwrap().first(), Some(&admin));
+        for admin in admins {
+            lines.remove_sender(admin, LINE + 1).unwrap();
         }
     }
-}
\ No newline at end of file
+}
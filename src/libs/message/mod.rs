@@ -3,11 +3,130 @@ pub mod redis_queue;
 pub mod queue_trait;
 pub mod line_manage;
 pub mod actix_port;
+pub mod retention;
+pub mod keys;
+pub mod signal;
 
 use serde_derive::{Deserialize, Serialize};
-#[derive(Debug, Serialize, Deserialize)]
+use crate::libs::at_rest::AtRest;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub line_id: u16,
+    /// Members receive the label of the sender in the line, see `AtRest::member_label`.
+    /// Empty for a sealed message, whose sender is only named inside the encrypted content.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub sender: String,
     pub content: String,
+    /// Seconds after sending when the message must no longer be delivered.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// Delete the message as soon as it has been delivered once.
+    /// Clients should also drop it after showing it.
+    #[serde(default)]
+    pub burn_after_reading: bool,
+    /// Sequence number in the queue that keeps the message until every device of the recipient
+    /// acknowledged it, live messages included. A client that received up to it resumes from there
+    /// after reconnecting. Absent in what clients send.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+/// A message as it is kept in a queue.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// Only recorded when the queue is not the sender's own, as in the inbox of a group member.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    pub content: String,
+    pub sent_at: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub burn_after_reading: bool,
+}
+
+impl StoredMessage {
+    /// `queue_owner` is who the queue that keeps the message belongs to.
+    pub fn new(message: Message, queue_owner: &str, now: u64) -> Self {
+        StoredMessage {
+            sender: (message.sender != queue_owner).then_some(message.sender),
+            content: message.content,
+            sent_at: Some(now),
+            expires_at: message.ttl.map(|ttl| now.saturating_add(ttl)),
+            burn_after_reading: message.burn_after_reading,
+        }
+    }
+
+    /// The message as members receive it, naming its sender by label.
+    pub fn into_message(self, at_rest: &AtRest, line_id: u16, queue_owner: &str, seq: Option<u64>, now: u64) -> Message {
+        Message {
+            line_id,
+            sender: sender_label(at_rest, line_id, self.sender.as_deref().unwrap_or(queue_owner)),
+            content: self.content,
+            ttl: self.expires_at.map(|expires_at| expires_at.saturating_sub(now)),
+            burn_after_reading: self.burn_after_reading,
+            seq,
+        }
+    }
+
+    /// What a burn-after-reading message is replaced with once it has been received.
+    /// It is expired, so it is never delivered again.
+    pub fn burned() -> Self {
+        StoredMessage {
+            sender: None,
+            content: String::new(),
+            sent_at: None,
+            expires_at: Some(0),
+            burn_after_reading: true,
+        }
+    }
+
+    pub fn encode(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|e| e.to_string())
+    }
+
+    /// Queues written before messages carried metadata hold the bare content.
+    pub fn decode(value: String) -> Self {
+        match serde_json::from_str(&value) {
+            Ok(stored) => stored,
+            Err(_) => StoredMessage {
+                sender: None,
+                content: value,
+                sent_at: None,
+                expires_at: None,
+                burn_after_reading: false,
+            },
+        }
+    }
+}
+
+/// What members are told about the sender of a message, whose identifier is its credential.
+/// A sealed message names no sender.
+pub fn sender_label(at_rest: &AtRest, line_id: u16, sender: &str) -> String {
+    if sender.is_empty() {
+        return String::new();
+    }
+    at_rest.member_label(line_id, sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn members_receive_the_label_of_the_sender() {
+        let at_rest = AtRest::default();
+        let stored = StoredMessage::new(Message {
+            line_id: 7,
+            sender: "alice".to_string(),
+            content: "hi".to_string(),
+            ttl: None,
+            burn_after_reading: false,
+            seq: None,
+        }, "bob", 0);
+        let message = stored.into_message(&at_rest, 7, "bob", Some(0), 0);
+        assert_eq!(message.sender, at_rest.member_label(7, "alice"));
+        assert_eq!(sender_label(&at_rest, 7, ""), "");
+    }
 }
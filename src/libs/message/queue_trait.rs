@@ -1,8 +1,47 @@
+use serde_derive::Serialize;
 use crate::libs::message::Message;
 
+/// A page of queued messages.
+/// Every message ever pushed to a queue has a sequence number, `next_cursor`
+/// is the sequence number following the last message of the page.
+#[derive(Debug, Serialize)]
+pub struct QueuedMessages {
+    pub messages: Vec<Message>,
+    pub next_cursor: u64,
+}
+
 pub trait MessageQueueStore<Config> {
     fn new(config: &Config) -> Result<Self, String> where Self: Sized;
-    fn push_message(&self, message: Message) -> Result<bool, String>;
-    fn pop_all(&self, line_id: u16, sender: &String) -> Result<Vec<Message>, String>;
-    fn get_head(&self, line_id: u16, sender: &String) -> Result<Message, String>;
-}
\ No newline at end of file
+    /// Append to the queue of `queue_owner`, which is the sender in a line of two
+    /// and the recipient in a group. Returns the sequence number of the message.
+    fn push_message(&self, queue_owner: &str, message: Message) -> Result<u64, String>;
+    /// Read the messages after `have_up_to`, acknowledging the messages up to it for `device`.
+    /// Without it, read the messages `device` has not acknowledged yet. A long queue is read
+    /// a page at a time, the rest is fetched after the last message read.
+    /// Nothing read is acknowledged, so it is replayed again until `device` acknowledges it.
+    fn replay(&self, line_id: u16, sender: &str, device: &str, have_up_to: Option<u64>) -> Result<Vec<Message>, String>;
+    /// Read up to `limit` messages starting at `cursor`, without removing them.
+    /// Backends may read fewer at once.
+    fn fetch(&self, line_id: u16, sender: &str, cursor: u64, limit: usize) -> Result<QueuedMessages, String>;
+    /// Sequence number of the first message `device` has not acknowledged.
+    /// A device seen for the first time is registered at the oldest message still queued,
+    /// so acknowledgements from other devices no longer remove what it has not received.
+    fn device_cursor(&self, line_id: u16, sender: &str, device: &str) -> Result<u64, String>;
+    /// Record that `device` received every message before `cursor`.
+    /// Messages every known device has received are removed.
+    fn ack(&self, line_id: u16, sender: &str, device: &str, cursor: u64) -> Result<(), String>;
+    /// Number of messages in the queue, including expired ones not yet removed.
+    fn depth(&self, line_id: u16, sender: &str) -> Result<u64, String>;
+    /// Number of messages `device` has not acknowledged, including expired ones not yet removed.
+    fn unacked(&self, line_id: u16, sender: &str, device: &str) -> Result<u64, String>;
+    /// Seconds until the queue expires, `None` when it never does.
+    fn ttl(&self, line_id: u16, sender: &str) -> Result<Option<u64>, String>;
+    /// Check that the backend answers, used by the readiness probe.
+    fn ping(&self) -> Result<(), String>;
+    /// Keep a server event for a recipient who is offline.
+    fn push_event(&self, line_id: u16, recipient: &str, event: String) -> Result<(), String>;
+    /// Take every event kept for a recipient.
+    fn pop_events(&self, line_id: u16, recipient: &str) -> Result<Vec<String>, String>;
+    /// Remove every message for every device. Sequence numbers keep counting from where they were.
+    fn purge(&self, line_id: u16, sender: &str) -> Result<(), String>;
+}
@@ -1,12 +1,143 @@
-use std::sync::{Arc, Mutex};
-use redis::{Commands, Client};
-use crate::libs::redis_connect::RedisConnection;
-use super::queue_trait::MessageQueueStore;
-use crate::libs::message::Message;
+use std::time::Duration;
+use redis::{Commands, Script};
+use crate::libs::redis_connect::{RedisConnection, RedisPool, AutoDeleteTime, apply_key_expiry, key_ttl};
+use super::queue_trait::{MessageQueueStore, QueuedMessages};
+use super::keys::{queue_key, queue_base_key, queue_cursors_key, queue_seen_key, event_queue_key};
+use super::retention::{key_expiry, is_expired, now_seconds};
+use crate::libs::message::{Message, StoredMessage};
+use crate::libs::metrics::{metrics, REDIS_BACKEND};
+use crate::libs::at_rest::AtRest;
+
+/// Queues written before messages had sequence numbers have no base KEYS[2] and hold their
+/// newest message first. Reverse them in place, so the oldest message is first like in any
+/// other queue, and give them a base. Runs before every script that reads or writes a queue.
+const MIGRATE_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[2]) == 0 then
+    local legacy = redis.call('LRANGE', KEYS[1], 0, -1)
+    if #legacy > 0 then
+        for i = 1, #legacy do
+            redis.call('LSET', KEYS[1], i - 1, legacy[#legacy - i + 1])
+        end
+        redis.call('SET', KEYS[2], 0)
+        local ttl = redis.call('PTTL', KEYS[1])
+        if ttl > 0 then redis.call('PEXPIRE', KEYS[2], ttl) end
+    end
+end
+";
+
+/// Append ARGV[1] to the queue KEYS[1] whose base is KEYS[2],
+/// returning its sequence number and the new length.
+/// A new queue gets its base right away, so it is never taken for one written before.
+const PUSH_SCRIPT: &str = r"
+redis.call('SET', KEYS[2], 0, 'NX')
+local length = redis.call('RPUSH', KEYS[1], ARGV[1])
+return {tonumber(redis.call('GET', KEYS[2])) + length - 1, length}
+";
+
+/// The base and length of the queue KEYS[1], and up to ARGV[2] messages from sequence number
+/// ARGV[1] on. A cursor before the base points at messages that were already acknowledged.
+const FETCH_SCRIPT: &str = r"
+local base = tonumber(redis.call('GET', KEYS[2]) or '0')
+local length = redis.call('LLEN', KEYS[1])
+local start = math.max(tonumber(ARGV[1]) - base, 0)
+if start >= length or tonumber(ARGV[2]) == 0 then return {base, length, {}} end
+return {base, length, redis.call('LRANGE', KEYS[1], start, start + tonumber(ARGV[2]) - 1)}
+";
+
+/// The cursor of device ARGV[1] in KEYS[3]. A device seen for the first time is registered
+/// at the base KEYS[2], so the messages still queued are kept until it acknowledges them too.
+/// KEYS[4] keeps when each device was last seen, ARGV[2] being now.
+const REGISTER_SCRIPT: &str = r"
+local base = tonumber(redis.call('GET', KEYS[2]) or '0')
+if redis.call('HSETNX', KEYS[3], ARGV[1], base) == 1 then
+    redis.call('HSET', KEYS[4], ARGV[1], ARGV[2])
+end
+return tonumber(redis.call('HGET', KEYS[3], ARGV[1]))
+";
+
+/// Drop every message from the queue KEYS[1] whose base is KEYS[2].
+const PURGE_SCRIPT: &str = r"
+local length = redis.call('LLEN', KEYS[1])
+if length == 0 then return 0 end
+redis.call('LTRIM', KEYS[1], length, -1)
+return redis.call('INCRBY', KEYS[2], length)
+";
+
+/// Move the cursor of device ARGV[1] in KEYS[3] up to ARGV[2], then drop the messages
+/// before the lowest cursor from the queue KEYS[1] whose base is KEYS[2].
+/// Burn-after-reading messages newly received by the device are replaced by ARGV[3],
+/// so no other device receives them.
+/// KEYS[4] keeps when each device last acknowledged, ARGV[4] being now. Devices that have not
+/// for ARGV[5] seconds are forgotten, so a device that went away can't keep the queue forever.
+const ACK_SCRIPT: &str = r"
+local base = tonumber(redis.call('GET', KEYS[2]) or '0')
+local length = redis.call('LLEN', KEYS[1])
+local cursor = math.min(tonumber(ARGV[2]), base + length)
+local previous = math.max(tonumber(redis.call('HGET', KEYS[3], ARGV[1]) or '0'), base)
+if cursor > previous then
+    local first = previous - base
+    local values = redis.call('LRANGE', KEYS[1], first, cursor - base - 1)
+    for i, value in ipairs(values) do
+        local ok, message = pcall(cjson.decode, value)
+        if ok and type(message) == 'table' and message.burn_after_reading == true then
+            redis.call('LSET', KEYS[1], first + i - 1, ARGV[3])
+        end
+    end
+    redis.call('HSET', KEYS[3], ARGV[1], cursor)
+end
+local now = tonumber(ARGV[4])
+redis.call('HSET', KEYS[4], ARGV[1], now)
+for _, device in ipairs(redis.call('HKEYS', KEYS[3])) do
+    local seen = redis.call('HGET', KEYS[4], device)
+    if not seen then
+        redis.call('HSET', KEYS[4], device, now)
+    elseif now - tonumber(seen) > tonumber(ARGV[5]) then
+        redis.call('HDEL', KEYS[3], device)
+        redis.call('HDEL', KEYS[4], device)
+    end
+end
+local lowest = nil
+for _, value in ipairs(redis.call('HVALS', KEYS[3])) do
+    local device_cursor = tonumber(value)
+    if lowest == nil or device_cursor < lowest then lowest = device_cursor end
+end
+if lowest == nil or lowest <= base then return base end
+redis.call('LTRIM', KEYS[1], lowest - base, -1)
+return redis.call('INCRBY', KEYS[2], lowest - base)
+";
+
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+/// A device that has not acknowledged anything for this long no longer holds messages back
+/// for itself. It starts over at the oldest message still queued when it comes back.
+const DEVICE_EXPIRY: u64 = 30 * 24 * 60 * 60;
+/// Most messages read from a queue at once, so a long queue is never read whole.
+const MAX_FETCH: usize = 1_000;
+/// Only the latest events are kept, older ones are of no use once they pile up.
+const MAX_QUEUED_EVENTS: isize = 100;
+
+/// A script on the queue KEYS[1] whose base is KEYS[2], run after `MIGRATE_SCRIPT`.
+fn queue_script(body: &str) -> Script {
+    Script::new(&[MIGRATE_SCRIPT, body].concat())
+}
 
 pub struct RedisQueue {
-    client: Arc<Mutex<Client>>,
-    auto_delete_time: Option<u64>
+    client: RedisPool,
+    auto_delete_time: AutoDeleteTime,
+    at_rest: AtRest,
+}
+
+impl RedisQueue {
+    /// The sender recorded with a message is encrypted like membership, see `at_rest`.
+    fn encode(&self, key: &str, mut stored: StoredMessage) -> Result<String, String> {
+        stored.sender = stored.sender.map(|sender| self.at_rest.seal(key, &sender)).transpose()?;
+        stored.encode()
+    }
+
+    fn decode(&self, key: &str, value: String) -> Result<StoredMessage, String> {
+        let mut stored = StoredMessage::decode(value);
+        stored.sender = stored.sender.map(|sender| self.at_rest.open(key, &sender)).transpose()?;
+        Ok(stored)
+    }
 }
 
 impl MessageQueueStore<RedisConnection> for RedisQueue {
@@ -14,49 +145,296 @@ impl MessageQueueStore<RedisConnection> for RedisQueue {
     fn new(config: &RedisConnection) -> Result<Self, String> {
         Ok(Self {
             client: config.get_client(),
-            auto_delete_time: config.auto_delete_time
+            auto_delete_time: config.auto_delete_time.clone(),
+            at_rest: config.at_rest.clone(),
         })
     }
 
-    fn push_message(&self, message: Message) -> Result<bool, String> {
-        let key = format!("line:{}:{}", message.line_id, message.sender);
-        let value = message.content;
-        let mut con = self.client.lock().unwrap().get_connection().map_err(|e| e.to_string())?;
+    fn push_message(&self, queue_owner: &str, message: Message) -> Result<u64, String> {
+        let owner = self.at_rest.member_name(message.line_id, queue_owner);
+        let key = queue_key(message.line_id, &owner);
+        let base_key = queue_base_key(message.line_id, &owner);
+        let cursors_key = queue_cursors_key(message.line_id, &owner);
+        let value = self.encode(&key, StoredMessage::new(message, queue_owner, now_seconds()))?;
+        let auto_delete_time = self.auto_delete_time.get();
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+
+        // Oldest message first, so that list index + base is the sequence number.
+        let (seq, depth): (u64, u64) = queue_script(PUSH_SCRIPT)
+            .key(&key)
+            .key(&base_key)
+            .arg(value)
+            .invoke(&mut con)
+            .map_err(|e| e.to_string())?;
+        metrics().queue_depth.with_label_values(&[REDIS_BACKEND]).observe(depth as f64);
+        apply_key_expiry(&mut con, &key, key_expiry(auto_delete_time))?;
+        apply_key_expiry(&mut con, &base_key, key_expiry(auto_delete_time))?;
+        apply_key_expiry(&mut con, &cursors_key, key_expiry(auto_delete_time))?;
+        Ok(seq)
+    }
 
-        con.lpush(&key, value).map_err(|e| e.to_string())?;
-        con.expire(&key, self.auto_delete_time.unwrap_or(0) as usize).map_err(|e| e.to_string())?; // If auto_delete_time is None, then the key will never expire
-        Ok(true)
+    fn replay(&self, line_id: u16, sender: &str, device: &str, have_up_to: Option<u64>) -> Result<Vec<Message>, String> {
+        let cursor = match have_up_to {
+            Some(seq) => {
+                let cursor = seq.saturating_add(1);
+                self.ack(line_id, sender, device, cursor)?;
+                cursor
+            }
+            None => self.device_cursor(line_id, sender, device)?,
+        };
+        Ok(self.fetch(line_id, sender, cursor, MAX_FETCH)?.messages)
     }
 
-    fn pop_all(&self, line_id: u16, sender: &String) -> Result<Vec<Message>, String> {
-        let key = format!("line:{}:{}", line_id, sender);
-        let mut con = self.client.lock().unwrap().get_connection().map_err(|e| e.to_string())?;
+    fn fetch(&self, line_id: u16, sender: &str, cursor: u64, limit: usize) -> Result<QueuedMessages, String> {
+        let owner = self.at_rest.member_name(line_id, sender);
+        let key = queue_key(line_id, &owner);
+        let base_key = queue_base_key(line_id, &owner);
+        let auto_delete_time = self.auto_delete_time.get();
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+
+        let (base, length, message_strings): (u64, u64, Vec<String>) = queue_script(FETCH_SCRIPT)
+            .key(&key)
+            .key(&base_key)
+            .arg(cursor)
+            .arg(limit.min(MAX_FETCH))
+            .invoke(&mut con)
+            .map_err(|e| e.to_string())?;
+
+        let start = cursor.saturating_sub(base).min(length);
+        let end = start + message_strings.len() as u64;
+
+        let now = now_seconds();
+        let mut messages = Vec::with_capacity(message_strings.len());
+        for (value, seq) in message_strings.into_iter().zip(base + start..) {
+            let stored = self.decode(&key, value)?;
+            if !is_expired(stored.sent_at, stored.expires_at, now, auto_delete_time) {
+                messages.push(stored.into_message(&self.at_rest, line_id, sender, Some(seq), now));
+            }
+        }
+        Ok(QueuedMessages {
+            messages,
+            next_cursor: base + end,
+        })
+    }
+
+    fn device_cursor(&self, line_id: u16, sender: &str, device: &str) -> Result<u64, String> {
+        let owner = self.at_rest.member_name(line_id, sender);
+        let cursors_key = queue_cursors_key(line_id, &owner);
+        let seen_key = queue_seen_key(line_id, &owner);
+        let auto_delete_time = self.auto_delete_time.get();
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+
+        let cursor = queue_script(REGISTER_SCRIPT)
+            .key(queue_key(line_id, &owner))
+            .key(queue_base_key(line_id, &owner))
+            .key(&cursors_key)
+            .key(&seen_key)
+            .arg(device)
+            .arg(now_seconds())
+            .invoke::<u64>(&mut con)
+            .map_err(|e| e.to_string())?;
+        apply_key_expiry(&mut con, &cursors_key, key_expiry(auto_delete_time))?;
+        apply_key_expiry(&mut con, &seen_key, key_expiry(auto_delete_time))?;
+        Ok(cursor)
+    }
+
+    fn ack(&self, line_id: u16, sender: &str, device: &str, cursor: u64) -> Result<(), String> {
+        let owner = self.at_rest.member_name(line_id, sender);
+        let key = queue_key(line_id, &owner);
+        let base_key = queue_base_key(line_id, &owner);
+        let cursors_key = queue_cursors_key(line_id, &owner);
+        let seen_key = queue_seen_key(line_id, &owner);
+        let auto_delete_time = self.auto_delete_time.get();
+        let tombstone = StoredMessage::burned().encode()?;
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+
+        queue_script(ACK_SCRIPT)
+            .key(&key)
+            .key(&base_key)
+            .key(&cursors_key)
+            .key(&seen_key)
+            .arg(device)
+            .arg(cursor)
+            .arg(tombstone)
+            .arg(now_seconds())
+            .arg(DEVICE_EXPIRY)
+            .invoke::<u64>(&mut con)
+            .map_err(|e| e.to_string())?;
+        apply_key_expiry(&mut con, &cursors_key, key_expiry(auto_delete_time))?;
+        apply_key_expiry(&mut con, &seen_key, key_expiry(auto_delete_time))
+    }
+
+    fn push_event(&self, line_id: u16, recipient: &str, event: String) -> Result<(), String> {
+        let key = event_queue_key(line_id, &self.at_rest.member_name(line_id, recipient));
+        let auto_delete_time = self.auto_delete_time.get();
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+
+        redis::pipe()
+            .atomic()
+            .rpush(&key, event).ignore()
+            .ltrim(&key, -MAX_QUEUED_EVENTS, -1).ignore()
+            .query::<()>(&mut con)
+            .map_err(|e| e.to_string())?;
+        apply_key_expiry(&mut con, &key, key_expiry(auto_delete_time))
+    }
 
-        let message_strings: Vec<String> = con.lrange(&key, 0, -1).map_err(|e| e.to_string())?;
-        con.ltrim(&key, -1, 0).map_err(|e| e.to_string())?; // This will keep the list empty after reading all messages
+    fn pop_events(&self, line_id: u16, recipient: &str) -> Result<Vec<String>, String> {
+        let key = event_queue_key(line_id, &self.at_rest.member_name(line_id, recipient));
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
 
-        let messages: Vec<Message> = message_strings.iter()
-            .map(|message_string| Message {
-                line_id,
-                sender: sender.clone(),
-                content: message_string.clone(),
-            })
-            .collect();
-        Ok(messages)
+        let (events,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .lrange(&key, 0, -1)
+            .del(&key).ignore()
+            .query(&mut con)
+            .map_err(|e| e.to_string())?;
+        Ok(events)
     }
 
-    fn get_head(&self, line_id: u16, sender: &String) -> Result<Message, String> {
-        let key = format!("line:{}:{}", line_id, sender);
-        let mut con = self.client.lock().unwrap().get_connection().map_err(|e| e.to_string())?;
+    fn purge(&self, line_id: u16, sender: &str) -> Result<(), String> {
+        let owner = self.at_rest.member_name(line_id, sender);
+        let key = queue_key(line_id, &owner);
+        let base_key = queue_base_key(line_id, &owner);
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
 
-        let head: Option<String> = con.lindex(&key, 0).map_err(|e| e.to_string())?;
-        match head {
-            Some(message) => Ok(Message {
-                line_id,
-                sender: sender.clone(),
-                content: message,
-            }),
-            None => Err(format!("No messages in the queue for line: {}, sender: {}", line_id, sender)),
+        Script::new(PURGE_SCRIPT)
+            .key(&key)
+            .key(&base_key)
+            .invoke::<u64>(&mut con)
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn depth(&self, line_id: u16, sender: &str) -> Result<u64, String> {
+        let key = queue_key(line_id, &self.at_rest.member_name(line_id, sender));
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+        con.llen(&key).map_err(|e| e.to_string())
+    }
+
+    fn unacked(&self, line_id: u16, sender: &str, device: &str) -> Result<u64, String> {
+        let owner = self.at_rest.member_name(line_id, sender);
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+        let (base, length, cursor): (Option<u64>, u64, Option<u64>) = redis::pipe()
+            .get(queue_base_key(line_id, &owner))
+            .llen(queue_key(line_id, &owner))
+            .hget(queue_cursors_key(line_id, &owner), device)
+            .query(&mut con)
+            .map_err(|e| e.to_string())?;
+        // A cursor before the base points at messages that were already acknowledged.
+        let received = cursor.unwrap_or(0).saturating_sub(base.unwrap_or(0)).min(length);
+        Ok(length - received)
+    }
+
+    fn ping(&self) -> Result<(), String> {
+        let mut con = self.client.client().get_connection_with_timeout(PING_TIMEOUT).map_err(|e| e.to_string())?;
+        con.set_read_timeout(Some(PING_TIMEOUT)).map_err(|e| e.to_string())?;
+        redis::cmd("PING").query::<String>(&mut con).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn ttl(&self, line_id: u16, sender: &str) -> Result<Option<u64>, String> {
+        let key = queue_key(line_id, &self.at_rest.member_name(line_id, sender));
+        let mut con = self.client.get_connection().map_err(|e| e.to_string())?;
+        key_ttl(&mut con, &key)
+    }
+}
+
+/// These need a Redis server: `REDIS_URL=redis://127.0.0.1/ cargo test -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::redis_connect::RedisConfig;
+
+    const LINE: u16 = 65_000;
+
+    fn queue() -> RedisQueue {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        let connection = RedisConnection::new(&RedisConfig {
+            url,
+            auto_delete_time: AutoDeleteTime::fixed(None),
+            at_rest: AtRest::default(),
+        }).unwrap();
+        RedisQueue::new(&connection).unwrap()
+    }
+
+    /// A queue owner no other run of the tests uses.
+    fn owner(name: &str) -> String {
+        format!("test-{}-{}-{}", name, std::process::id(), now_seconds())
+    }
+
+    fn message(owner: &str, content: &str, burn_after_reading: bool) -> Message {
+        Message {
+            line_id: LINE,
+            sender: owner.to_string(),
+            content: content.to_string(),
+            ttl: None,
+            burn_after_reading,
+            seq: None,
         }
     }
+
+    #[test]
+    #[ignore = "needs a Redis server"]
+    fn burn_after_reading_is_gone_after_the_first_ack() {
+        let queue = queue();
+        let owner = owner("burn");
+        queue.push_message(&owner, message(&owner, "secret", true)).unwrap();
+        queue.push_message(&owner, message(&owner, "kept", false)).unwrap();
+
+        let fetched = queue.fetch(LINE, &owner, 0, 10).unwrap();
+        assert_eq!(fetched.messages.len(), 2);
+        queue.ack(LINE, &owner, "phone", fetched.next_cursor).unwrap();
+
+        let contents: Vec<String> = queue.fetch(LINE, &owner, 0, 10).unwrap().messages.into_iter().map(|m| m.content).collect();
+        assert!(!contents.contains(&"secret".to_string()));
+        queue.purge(LINE, &owner).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server"]
+    fn a_silent_device_stops_holding_the_queue() {
+        let queue = queue();
+        let owner = owner("silent");
+        assert_eq!(queue.push_message(&owner, message(&owner, "one", false)).unwrap(), 0);
+        assert_eq!(queue.push_message(&owner, message(&owner, "two", false)).unwrap(), 1);
+        queue.ack(LINE, &owner, "old", 1).unwrap();
+        let mut con = queue.client.get_connection().unwrap();
+        let _: () = con.hset(queue_seen_key(LINE, &owner), "old", now_seconds() - DEVICE_EXPIRY - 1).unwrap();
+
+        queue.ack(LINE, &owner, "phone", 2).unwrap();
+        assert!(queue.fetch(LINE, &owner, 0, 10).unwrap().messages.is_empty());
+        assert_eq!(queue.device_cursor(LINE, &owner, "old").unwrap(), 2);
+        queue.purge(LINE, &owner).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server"]
+    fn a_device_that_has_fetched_keeps_its_messages_from_another_devices_ack() {
+        let queue = queue();
+        let owner = owner("register");
+        queue.push_message(&owner, message(&owner, "one", false)).unwrap();
+        assert_eq!(queue.device_cursor(LINE, &owner, "laptop").unwrap(), 0);
+
+        queue.ack(LINE, &owner, "phone", 1).unwrap();
+        let contents: Vec<String> = queue.fetch(LINE, &owner, 0, 10).unwrap().messages.into_iter().map(|m| m.content).collect();
+        assert_eq!(contents, ["one"]);
+        queue.purge(LINE, &owner).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server"]
+    fn queues_written_newest_first_are_read_oldest_first() {
+        let queue = queue();
+        let owner = owner("legacy");
+        let key = queue_key(LINE, &owner);
+        let mut con = queue.client.get_connection().unwrap();
+        let _: () = con.lpush(&key, "first").unwrap();
+        let _: () = con.lpush(&key, "second").unwrap();
+        queue.push_message(&owner, message(&owner, "third", false)).unwrap();
+
+        let fetched = queue.fetch(LINE, &owner, 0, 10).unwrap();
+        let contents: Vec<String> = fetched.messages.into_iter().map(|m| m.content).collect();
+        assert_eq!(contents, ["first", "second", "third"]);
+        assert_eq!(fetched.next_cursor, 3);
+        queue.purge(LINE, &owner).unwrap();
+    }
 }
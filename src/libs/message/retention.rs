@@ -0,0 +1,106 @@
+//! Retention rules for Auto Delete, shared by every storage backend.
+//!
+//! When Auto Delete is enabled with a time of `T` seconds:
+//! - A line's membership record expires `T` seconds after the line was last used,
+//!   i.e. after the last join, exit or message sent in it.
+//! - A sender's queue expires `T` seconds after the last message was pushed to it.
+//! - A queued message older than `T` seconds is never delivered, even when its queue
+//!   is kept alive by newer messages.
+//!
+//! When Auto Delete is disabled, none of the above expires.
+//!
+//! Independently of Auto Delete, a sender may give a message its own TTL.
+//! The message is never delivered after that TTL, even if it is still in a queue.
+//!
+//! Delivering a queued message does not acknowledge it, it is replayed on every join
+//! of a device until that device acknowledges it, or resumes after it.
+//! A queued message stays until every device of the recipient has acknowledged it,
+//! except for a burn-after-reading message, which is erased once any device has.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a backend must do with the TTL of a key after writing to it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum KeyExpiry {
+    ExpireIn(u64),
+    Persist,
+}
+
+pub fn key_expiry(auto_delete_time: Option<u64>) -> KeyExpiry {
+    match auto_delete_time {
+        Some(time) => KeyExpiry::ExpireIn(time),
+        None => KeyExpiry::Persist,
+    }
+}
+
+/// Whether a message sent at `sent_at` is too old to be delivered at `now`,
+/// either by its own `expires_at` or by the Auto Delete time.
+/// Messages without a timestamp were queued before timestamps were recorded,
+/// and are left to the expiry of their queue.
+pub fn is_expired(sent_at: Option<u64>, expires_at: Option<u64>, now: u64, auto_delete_time: Option<u64>) -> bool {
+    if let Some(expires_at) = expires_at {
+        if now >= expires_at {
+            return true;
+        }
+    }
+    match (sent_at, auto_delete_time) {
+        (Some(sent_at), Some(time)) => now.saturating_sub(sent_at) >= time,
+        _ => false,
+    }
+}
+
+pub fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enabled_auto_delete_expires_keys() {
+        assert_eq!(key_expiry(Some(604_800)), KeyExpiry::ExpireIn(604_800));
+    }
+
+    #[test]
+    fn disabled_auto_delete_persists_keys() {
+        assert_eq!(key_expiry(None), KeyExpiry::Persist);
+    }
+
+    #[test]
+    fn enabled_auto_delete_expires_old_messages() {
+        assert!(!is_expired(Some(1_000), None, 1_059, Some(60)));
+        assert!(is_expired(Some(1_000), None, 1_060, Some(60)));
+        assert!(is_expired(Some(1_000), None, 9_999, Some(60)));
+    }
+
+    #[test]
+    fn disabled_auto_delete_keeps_old_messages() {
+        assert!(!is_expired(Some(0), None, u64::MAX, None));
+    }
+
+    #[test]
+    fn messages_without_timestamp_never_expire_by_age() {
+        assert!(!is_expired(None, None, u64::MAX, Some(60)));
+    }
+
+    #[test]
+    fn clock_going_backwards_does_not_expire() {
+        assert!(!is_expired(Some(2_000), None, 1_000, Some(60)));
+    }
+
+    #[test]
+    fn message_ttl_expires_before_auto_delete() {
+        assert!(!is_expired(Some(1_000), Some(1_010), 1_009, Some(60)));
+        assert!(is_expired(Some(1_000), Some(1_010), 1_010, Some(60)));
+    }
+
+    #[test]
+    fn message_ttl_applies_when_auto_delete_is_disabled() {
+        assert!(!is_expired(Some(1_000), Some(1_010), 1_009, None));
+        assert!(is_expired(Some(1_000), Some(1_010), 1_010, None));
+    }
+}
@@ -0,0 +1,53 @@
+//! Ephemeral signals such as typing indicators.
+//! They are relayed to the peers that are online and dropped otherwise, never stored.
+//!
+//! The call kinds carry WebRTC signalling between the two members of a line, so peers
+//! can call each other directly. Their content is the SDP or ICE candidate, encrypted
+//! by the clients and opaque to the server.
+
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalKind {
+    Typing,
+    Presence,
+    /// A hint about a call, e.g. that the sender is about to ring.
+    CallHint,
+    Offer,
+    Answer,
+    IceCandidate,
+    /// Ends or declines a call.
+    Hangup,
+}
+
+impl SignalKind {
+    /// Label of the kind in metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SignalKind::Typing => "typing",
+            SignalKind::Presence => "presence",
+            SignalKind::CallHint => "call_hint",
+            SignalKind::Offer => "offer",
+            SignalKind::Answer => "answer",
+            SignalKind::IceCandidate => "ice_candidate",
+            SignalKind::Hangup => "hangup",
+        }
+    }
+
+    /// Whether the signal belongs to a call, which needs the peer to be online.
+    pub fn is_call(&self) -> bool {
+        matches!(self, SignalKind::Offer | SignalKind::Answer | SignalKind::IceCandidate | SignalKind::Hangup)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signal {
+    pub line_id: u16,
+    /// Peers receive the label of the sender in the line, see `AtRest::member_label`.
+    pub sender: String,
+    pub kind: SignalKind,
+    /// Opaque to the server, clients may encrypt it like message content.
+    #[serde(default)]
+    pub content: String,
+}
@@ -0,0 +1,120 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// Label values of the storage backends.
+pub const REDIS_BACKEND: &str = "redis";
+pub const FILESYSTEM_BACKEND: &str = "filesystem";
+
+const QUEUE_DEPTH_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+
+pub struct Metrics {
+    registry: Registry,
+    pub online_sessions: IntGaugeVec,
+    pub lines_by_occupancy: IntGaugeVec,
+    pub messages_received: IntCounterVec,
+    pub messages_delivered_live: IntCounterVec,
+    pub messages_queued: IntCounterVec,
+    pub queue_depth: HistogramVec,
+    pub join_line_results: IntCounterVec,
+    pub signals: IntCounterVec,
+    pub wake_ups: IntCounterVec,
+    pub storage_latency: HistogramVec,
+    pub storage_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("pcp".to_string()), None)?;
+        let metrics = Metrics {
+            online_sessions: IntGaugeVec::new(
+                Opts::new("online_sessions", "Connected WebSocket sessions."),
+                &["backend"],
+            )?,
+            lines_by_occupancy: IntGaugeVec::new(
+                Opts::new("lines", "Lines by number of senders in them, as of the last count."),
+                &["backend", "occupancy"],
+            )?,
+            messages_received: IntCounterVec::new(
+                Opts::new("messages_received_total", "Messages accepted from senders."),
+                &["backend"],
+            )?,
+            messages_delivered_live: IntCounterVec::new(
+                Opts::new("messages_delivered_live_total", "Messages delivered straight to an online session."),
+                &["backend"],
+            )?,
+            messages_queued: IntCounterVec::new(
+                Opts::new("messages_queued_total", "Messages pushed to a queue."),
+                &["backend"],
+            )?,
+            queue_depth: HistogramVec::new(
+                HistogramOpts::new("queue_depth", "Length of a queue after a message was pushed to it.")
+                    .buckets(QUEUE_DEPTH_BUCKETS.to_vec()),
+                &["backend"],
+            )?,
+            join_line_results: IntCounterVec::new(
+                Opts::new("join_line_total", "Outcomes of joining a line."),
+                &["backend", "result"],
+            )?,
+            signals: IntCounterVec::new(
+                Opts::new("signals_total", "Ephemeral signals, relayed to an online peer or dropped."),
+                &["kind", "outcome"],
+            )?,
+            wake_ups: IntCounterVec::new(
+                Opts::new("wake_ups_total", "Wake-ups of offline devices, sent, failed, dropped or coalesced into a pending one."),
+                &["outcome"],
+            )?,
+            storage_latency: HistogramVec::new(
+                HistogramOpts::new("storage_latency_seconds", "Latency of storage operations."),
+                &["backend", "operation"],
+            )?,
+            storage_errors: IntCounterVec::new(
+                Opts::new("storage_errors_total", "Failed storage operations."),
+                &["backend", "operation"],
+            )?,
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.online_sessions.clone()))?;
+        metrics.registry.register(Box::new(metrics.lines_by_occupancy.clone()))?;
+        metrics.registry.register(Box::new(metrics.messages_received.clone()))?;
+        metrics.registry.register(Box::new(metrics.messages_delivered_live.clone()))?;
+        metrics.registry.register(Box::new(metrics.messages_queued.clone()))?;
+        metrics.registry.register(Box::new(metrics.queue_depth.clone()))?;
+        metrics.registry.register(Box::new(metrics.join_line_results.clone()))?;
+        metrics.registry.register(Box::new(metrics.signals.clone()))?;
+        metrics.registry.register(Box::new(metrics.wake_ups.clone()))?;
+        metrics.registry.register(Box::new(metrics.storage_latency.clone()))?;
+        metrics.registry.register(Box::new(metrics.storage_errors.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn gather(&self) -> Result<String, String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Failed to register metrics."))
+}
+
+/// Run a storage operation, recording how long it took and whether it failed.
+pub fn observe_storage<T>(backend: &str, operation: &str, f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    let start = Instant::now();
+    let result = f();
+    let metrics = metrics();
+    metrics.storage_latency
+        .with_label_values(&[backend, operation])
+        .observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        metrics.storage_errors.with_label_values(&[backend, operation]).inc();
+    }
+    result
+}
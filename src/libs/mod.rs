@@ -2,5 +2,14 @@ pub mod message;
 pub mod parse_config;
 mod redis_connect;
 pub mod load_config;
+pub mod live_config;
 pub mod core;
+pub mod metrics;
+pub mod logging;
+pub mod shutdown;
+pub mod cluster;
+pub mod blob;
+pub mod notifier;
+pub mod at_rest;
+pub mod sealed;
 pub mod ws;
\ No newline at end of file
@@ -0,0 +1,427 @@
+//! Wake-ups for recipients who were offline when a message was queued for them.
+//!
+//! Each device of a sender may register an endpoint, such as a UnifiedPush endpoint.
+//! When a message is queued for the sender, every endpoint is sent a wake-up that carries
+//! no content at all, so the device knows to connect and fetch.
+//!
+//! Wake-ups are coalesced and rate-limited per endpoint: a burst of messages results in
+//! one wake-up after `Coalesce Delay`, and an endpoint is woken at most once per `Min Interval`.
+//!
+//! Endpoints are chosen by clients, so wake-ups are only sent to public addresses,
+//! see `EndpointPolicy`.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use redis::Commands;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+
+use super::message::keys::push_endpoints_key;
+use super::message::retention::key_expiry;
+use super::metrics::{metrics, observe_storage, REDIS_BACKEND};
+use super::redis_connect::{RedisConnection, RedisPool, PooledConnection, AutoDeleteTime, apply_key_expiry};
+use super::at_rest::AtRest;
+
+/// Body of every wake-up. It tells the device nothing but to connect.
+const WAKE_UP_BODY: &str = r#"{"type":"wake_up"}"#;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_ENDPOINT_LEN: usize = 2048;
+/// Threads sending wake-ups, so a slow endpoint only holds up one of them.
+const NOTIFY_WORKERS: usize = 4;
+/// Wake-ups waiting for a worker. More are dropped rather than piling up behind slow endpoints.
+const MAX_WAITING_WAKE_UPS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotifyConfig {
+    #[serde(rename = "Enabled", default)]
+    pub enabled: bool,
+    /// Shortest time between two wake-ups of the same endpoint.
+    #[serde(rename = "Min Interval", default = "default_min_interval")]
+    pub min_interval: String,
+    /// How long to wait for more messages before waking an endpoint.
+    #[serde(rename = "Coalesce Delay", default = "default_coalesce_delay")]
+    pub coalesce_delay: String,
+    /// Hosts that may be woken even though they resolve to private addresses,
+    /// such as a distributor on the local network.
+    #[serde(rename = "Allowed Hosts", default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+fn default_min_interval() -> String {
+    "30s".to_string()
+}
+
+fn default_coalesce_delay() -> String {
+    "2s".to_string()
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            enabled: false,
+            min_interval: default_min_interval(),
+            coalesce_delay: default_coalesce_delay(),
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+/// Delivers a wake-up to an endpoint.
+pub trait Notifier: Send + Sync + 'static {
+    fn notify(&self, endpoint: &str) -> Result<(), String>;
+}
+
+/// POSTs the wake-up to the endpoint, as a UnifiedPush distributor expects.
+pub struct WebhookNotifier {
+    agent: ureq::Agent,
+}
+
+impl WebhookNotifier {
+    pub fn new(policy: EndpointPolicy) -> Self {
+        WebhookNotifier {
+            // Redirects would lead anywhere, and every lookup goes through the policy.
+            agent: ureq::AgentBuilder::new().timeout(WEBHOOK_TIMEOUT).redirects(0).resolver(policy).build(),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, endpoint: &str) -> Result<(), String> {
+        self.agent.post(endpoint)
+            .set("Content-Type", "application/json")
+            .send_string(WAKE_UP_BODY)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Which endpoints may be woken. Only absolute HTTP(S) URLs whose host resolves to a public
+/// address are, unless the operator allowed the host. Checked when an endpoint is registered,
+/// and again on every lookup when waking it, since what a name resolves to can change.
+#[derive(Clone, Default)]
+pub struct EndpointPolicy {
+    allowed_hosts: Arc<HashSet<String>>,
+}
+
+impl EndpointPolicy {
+    pub fn new(allowed_hosts: &[String]) -> Self {
+        EndpointPolicy {
+            allowed_hosts: Arc::new(allowed_hosts.iter().map(|host| host.to_ascii_lowercase()).collect()),
+        }
+    }
+
+    pub fn validate_endpoint(&self, endpoint: &str) -> bool {
+        if endpoint.len() > MAX_ENDPOINT_LEN || endpoint.contains(char::is_whitespace) {
+            return false;
+        }
+        let url = match url::Url::parse(endpoint) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => url,
+            _ => return false,
+        };
+        match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => self.addresses(host, port).is_ok_and(|addresses| !addresses.is_empty()),
+            _ => false,
+        }
+    }
+
+    /// The addresses of `host` a wake-up may be sent to.
+    fn addresses(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let addresses = (host.as_str(), port).to_socket_addrs()?;
+        if self.allowed_hosts.contains(&host) {
+            return Ok(addresses.collect());
+        }
+        Ok(addresses.filter(|address| is_public(address.ip())).collect())
+    }
+}
+
+impl ureq::Resolver for EndpointPolicy {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = netloc.rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "endpoint without a port"))?;
+        self.addresses(host, port)
+    }
+}
+
+/// Whether `ip` is on the internet, rather than the server itself or a network it is on.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+                || first == 0
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10.
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || ip.is_unique_local() || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+/// Decides when each endpoint is woken.
+struct Schedule {
+    min_interval: Duration,
+    coalesce_delay: Duration,
+    /// Endpoints waiting to be woken, and when.
+    pending: HashMap<String, Instant>,
+    last_woken: HashMap<String, Instant>,
+}
+
+impl Schedule {
+    fn new(min_interval: Duration, coalesce_delay: Duration) -> Self {
+        Schedule { min_interval, coalesce_delay, pending: HashMap::new(), last_woken: HashMap::new() }
+    }
+
+    /// Returns `false` when the wake-up joins one that is already pending.
+    fn add(&mut self, endpoint: String, now: Instant) -> bool {
+        if self.pending.contains_key(&endpoint) {
+            return false;
+        }
+        let mut due = now + self.coalesce_delay;
+        if let Some(last_woken) = self.last_woken.get(&endpoint) {
+            due = due.max(*last_woken + self.min_interval);
+        }
+        self.pending.insert(endpoint, due);
+        true
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.pending.values().min().copied()
+    }
+
+    /// Take the endpoints due at `now`, recording that they were woken.
+    fn take_due(&mut self, now: Instant) -> Vec<String> {
+        let due: Vec<String> = self.pending.iter()
+            .filter(|(_, due)| **due <= now)
+            .map(|(endpoint, _)| endpoint.clone())
+            .collect();
+        for endpoint in &due {
+            self.pending.remove(endpoint);
+            self.last_woken.insert(endpoint.clone(), now);
+        }
+        let min_interval = self.min_interval;
+        self.last_woken.retain(|_, woken| now.duration_since(*woken) < min_interval);
+        due
+    }
+}
+
+/// Schedules wake-ups on a thread of its own and sends them from `NOTIFY_WORKERS` others,
+/// so a slow endpoint never holds up delivery, nor the wake-ups of other endpoints for long.
+pub struct WakeUps {
+    endpoints: mpsc::Sender<String>,
+    policy: EndpointPolicy,
+}
+
+impl WakeUps {
+    pub fn spawn(notifier: impl Notifier, policy: EndpointPolicy, min_interval: Duration, coalesce_delay: Duration) -> Result<Self, String> {
+        let notifier = Arc::new(notifier);
+        let (due_sender, due) = mpsc::sync_channel(MAX_WAITING_WAKE_UPS);
+        let due = Arc::new(Mutex::new(due));
+        for index in 0..NOTIFY_WORKERS {
+            let (notifier, due) = (notifier.clone(), due.clone());
+            thread::Builder::new()
+                .name(format!("notifier-{}", index))
+                .spawn(move || send(&*notifier, &due))
+                .map_err(|e| e.to_string())?;
+        }
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("notifier".to_string())
+            .spawn(move || run(receiver, Schedule::new(min_interval, coalesce_delay), due_sender))
+            .map_err(|e| e.to_string())?;
+        Ok(WakeUps { endpoints: sender, policy })
+    }
+
+    pub fn wake(&self, endpoint: String) {
+        if self.endpoints.send(endpoint).is_err() {
+            error!("Notifier is not running");
+        }
+    }
+
+    /// Whether `endpoint` may be registered, see `EndpointPolicy`.
+    pub fn validate_endpoint(&self, endpoint: &str) -> bool {
+        self.policy.validate_endpoint(endpoint)
+    }
+}
+
+fn run(endpoints: mpsc::Receiver<String>, mut schedule: Schedule, due: mpsc::SyncSender<String>) {
+    loop {
+        let received = match schedule.next_due() {
+            Some(due) => endpoints.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => endpoints.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(endpoint) => {
+                if !schedule.add(endpoint, Instant::now()) {
+                    metrics().wake_ups.with_label_values(&["coalesced"]).inc();
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        for endpoint in schedule.take_due(Instant::now()) {
+            match due.try_send(endpoint) {
+                Ok(()) => {}
+                Err(mpsc::TrySendError::Full(_)) => {
+                    warn!("Too many wake-ups waiting, dropping one");
+                    metrics().wake_ups.with_label_values(&["dropped"]).inc();
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => return,
+            }
+        }
+    }
+}
+
+fn send(notifier: &impl Notifier, due: &Mutex<mpsc::Receiver<String>>) {
+    loop {
+        // Released before sending, so the other workers can take the next one meanwhile.
+        let endpoint = match due.lock().unwrap().recv() {
+            Ok(endpoint) => endpoint,
+            Err(_) => return,
+        };
+        match notifier.notify(&endpoint) {
+            Ok(()) => {
+                debug!("Woke a device");
+                metrics().wake_ups.with_label_values(&["sent"]).inc();
+            }
+            Err(e) => {
+                warn!("Failed to wake a device: {}", e);
+                metrics().wake_ups.with_label_values(&["failed"]).inc();
+            }
+        }
+    }
+}
+
+/// Endpoints registered by the devices of each sender.
+pub struct PushEndpoints {
+    client: RedisPool,
+    auto_delete_time: AutoDeleteTime,
+    at_rest: AtRest,
+}
+
+impl PushEndpoints {
+    pub fn new(config: &RedisConnection) -> Self {
+        PushEndpoints {
+            client: config.get_client(),
+            auto_delete_time: config.auto_delete_time.clone(),
+            at_rest: config.at_rest.clone(),
+        }
+    }
+
+    fn key(&self, sender: &str) -> String {
+        push_endpoints_key(&self.at_rest.sender_name(sender))
+    }
+
+    fn connection(&self) -> Result<PooledConnection, String> {
+        self.client.get_connection().map_err(|e| e.to_string())
+    }
+
+    /// Registering again counts as using the endpoints, which expire like a queue otherwise.
+    /// Endpoints are encrypted like membership, see `at_rest`.
+    pub fn register(&self, sender: &str, device: &str, endpoint: &str) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "register_push_endpoint", || {
+            let mut con = self.connection()?;
+            let key = self.key(sender);
+            let endpoint = self.at_rest.seal(&key, endpoint)?;
+            con.hset::<_, _, _, ()>(&key, device, endpoint).map_err(|e| e.to_string())?;
+            apply_key_expiry(&mut con, &key, key_expiry(self.auto_delete_time.get()))
+        })
+    }
+
+    pub fn unregister(&self, sender: &str, device: &str) -> Result<(), String> {
+        observe_storage(REDIS_BACKEND, "unregister_push_endpoint", || {
+            let mut con = self.connection()?;
+            con.hdel(self.key(sender), device).map_err(|e| e.to_string())
+        })
+    }
+
+    pub fn endpoints(&self, sender: &str) -> Result<Vec<String>, String> {
+        observe_storage(REDIS_BACKEND, "get_push_endpoints", || {
+            let mut con = self.connection()?;
+            let key = self.key(sender);
+            let endpoints: Vec<String> = con.hvals(&key).map_err(|e| e.to_string())?;
+            endpoints.iter().map(|endpoint| self.at_rest.open(&key, endpoint)).collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn wake_ups_are_coalesced_and_rate_limited() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(Duration::from_secs(30), Duration::from_secs(2));
+        assert!(schedule.add("a".to_string(), start));
+        assert!(!schedule.add("a".to_string(), start + Duration::from_secs(1)));
+        assert!(schedule.take_due(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(schedule.take_due(start + Duration::from_secs(2)), vec!["a".to_string()]);
+
+        // Woken at 2s, so not again before 32s.
+        assert!(schedule.add("a".to_string(), start + Duration::from_secs(3)));
+        assert_eq!(schedule.next_due(), Some(start + Duration::from_secs(32)));
+        assert!(schedule.take_due(start + Duration::from_secs(31)).is_empty());
+        assert_eq!(schedule.take_due(start + Duration::from_secs(32)), vec!["a".to_string()]);
+    }
+
+    #[test]
+    fn webhook_posts_a_content_free_wake_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/push/abc", listener.local_addr().unwrap());
+        let stub = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (request_line, String::from_utf8(body).unwrap())
+        });
+
+        assert!(WebhookNotifier::new(EndpointPolicy::default()).notify(&endpoint).is_err());
+        WebhookNotifier::new(EndpointPolicy::new(&["127.0.0.1".to_string()])).notify(&endpoint).unwrap();
+        let (request_line, body) = stub.join().unwrap();
+        assert!(request_line.starts_with("POST /push/abc "));
+        assert_eq!(body, WAKE_UP_BODY);
+    }
+
+    #[test]
+    fn endpoints_must_be_public_http_urls() {
+        let policy = EndpointPolicy::default();
+        assert!(policy.validate_endpoint("https://93.184.216.34/up?token=abc"));
+        assert!(policy.validate_endpoint("http://[2606:4700::1111]:8080/up"));
+        assert!(!policy.validate_endpoint("file:///etc/passwd"));
+        assert!(!policy.validate_endpoint("https://93.184.216.34/a b"));
+        for endpoint in [
+            "http://127.0.0.1/", "http://localhost:8080/", "http://10.1.2.3/", "http://169.254.169.254/latest",
+            "http://0.0.0.0/", "http://100.64.0.1/", "http://[::1]/", "http://[fd00::1]/", "http://[::ffff:192.168.0.1]/",
+        ] {
+            assert!(!policy.validate_endpoint(endpoint), "{}", endpoint);
+        }
+        assert!(EndpointPolicy::new(&["127.0.0.1".to_string()]).validate_endpoint("http://127.0.0.1:8080/up"));
+    }
+}
@@ -1,4 +1,9 @@
 use serde_derive::{Deserialize, Serialize};
+use super::logging::LogConfig;
+use super::cluster::ClusterConfig;
+use super::blob::BlobConfig;
+use super::notifier::NotifyConfig;
+use super::at_rest::AtRestConfig;
 
 const PATH: &str = "./config/config.json";
 
@@ -7,18 +12,28 @@ pub struct Config {
     pub profile: Profile,
     pub database: Database,
     pub config: DetailedConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+    #[serde(default)]
+    pub blobs: BlobConfig,
+    #[serde(default)]
+    pub notifications: NotifyConfig,
+    #[serde(default)]
+    pub at_rest: AtRestConfig,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Profile {
     #[serde(rename = "Server Name")]
-    server_name: String,
+    pub server_name: String,
     #[serde(rename = "Server Description")]
-    server_description: String,
+    pub server_description: String,
     #[serde(rename = "Admin Contact")]
-    admin_contact: String,
+    pub admin_contact: String,
     #[serde(rename = "Server Location")]
-    server_location: String,
+    pub server_location: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,6 +49,40 @@ pub struct DetailedConfig {
     pub(crate) auto_delete: bool,
     #[serde(rename = "Auto Delete Time")]
     pub(crate) auto_delete_time: String,
+    /// Largest accepted message content, in bytes.
+    #[serde(rename = "Max Message Size", default = "default_max_message_size")]
+    pub(crate) max_message_size: usize,
+    /// Whether anyone may open a new line. Joining existing lines is always allowed.
+    #[serde(rename = "Open Registration", default = "default_open_registration")]
+    pub(crate) open_registration: bool,
+    /// Largest member cap a group line may be opened with.
+    #[serde(rename = "Max Group Members", default = "default_max_group_members")]
+    pub(crate) max_group_members: usize,
+    /// How long a shutdown may take to drain sessions before the server exits.
+    #[serde(rename = "Shutdown Timeout", default = "default_shutdown_timeout")]
+    pub(crate) shutdown_timeout: String,
+    /// Bearer token of the admin API, which is disabled when this is absent.
+    #[serde(rename = "Admin Token", default)]
+    pub(crate) admin_token: Option<String>,
+    /// Key of the delivery tokens of sealed sender, which is disabled when this is absent.
+    #[serde(rename = "Sealed Sender Secret", default)]
+    pub(crate) sealed_sender_secret: Option<String>,
+}
+
+fn default_max_message_size() -> usize {
+    64 * 1024
+}
+
+fn default_open_registration() -> bool {
+    true
+}
+
+fn default_max_group_members() -> usize {
+    16
+}
+
+fn default_shutdown_timeout() -> String {
+    "30s".to_string()
 }
 
 pub fn parse_config() -> Result<Config,String> {
@@ -42,24 +91,126 @@ pub fn parse_config() -> Result<Config,String> {
     Ok(config)
 }
 
+pub fn config_modified_time() -> Result<std::time::SystemTime, String> {
+    let metadata = std::fs::metadata(PATH).map_err(|e| e.to_string())?;
+    metadata.modified().map_err(|e| e.to_string())
+}
+
+/// Parse a humantime-style duration such as `"1w"`, `"36h"` or `"1d 12h"` into seconds.
+///
+/// A duration is one or more `<number><unit>` parts, optionally separated by whitespace.
+/// Units are case-insensitive. `m` has always meant months, so minutes are only spelled `min`.
+/// Returns `None` for empty input, unknown units or when the result would overflow `u64`.
 pub fn time_str_to_seconds(input: &str) -> Option<u64> {
     let input = input.trim().to_lowercase();
 
     // Define constants
+    const SECONDS_IN_MINUTE: u64 = 60;
+    const SECONDS_IN_HOUR: u64 = 3_600;
     const SECONDS_IN_DAY: u64 = 86_400;
     const SECONDS_IN_WEEK: u64 = 604_800;
-    const SECONDS_IN_MONTH: u64 = 2_628_000; // Average seconds in a month (30.44 days per month)
+    const SECONDS_IN_MONTH: u64 = 2_628_000; // Average seconds in a month (30.42 days per month)
     const SECONDS_IN_YEAR: u64 = 31_536_000; // 365 days per year
 
-    // Check the last character to determine the unit
-    let (num_str, unit) = input.split_at(input.len() - 1);
-    let number: u64 = num_str.parse().ok()?;
+    if input.is_empty() {
+        return None;
+    }
+
+    let mut total: u64 = 0;
+    let mut rest = input.as_str();
+    while !rest.is_empty() {
+        // Split the number from the front of the remaining input
+        let num_end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if num_end == 0 {
+            return None;
+        }
+        let number: u64 = rest[..num_end].parse().ok()?;
+        rest = rest[num_end..].trim_start();
+
+        // Then the unit, which runs until the next digit or whitespace
+        let unit_end = rest.find(|c: char| c.is_ascii_digit() || c.is_whitespace()).unwrap_or(rest.len());
+        let unit = &rest[..unit_end];
+        rest = rest[unit_end..].trim_start();
 
-    match unit {
-        "d" | "day" | "days" => Some(number * SECONDS_IN_DAY),
-        "w" | "week" | "weeks" => Some(number * SECONDS_IN_WEEK),
-        "m" | "month" | "months" => Some(number * SECONDS_IN_MONTH),
-        "y" | "year" | "years" => Some(number * SECONDS_IN_YEAR),
-        _ => None,
+        let multiplier = match unit {
+            "s" | "sec" | "secs" | "second" | "seconds" => 1,
+            "min" | "mins" | "minute" | "minutes" => SECONDS_IN_MINUTE,
+            "h" | "hr" | "hrs" | "hour" | "hours" => SECONDS_IN_HOUR,
+            "d" | "day" | "days" => SECONDS_IN_DAY,
+            "w" | "week" | "weeks" => SECONDS_IN_WEEK,
+            "m" | "month" | "months" => SECONDS_IN_MONTH,
+            "y" | "year" | "years" => SECONDS_IN_YEAR,
+            _ => return None,
+        };
+        total = total.checked_add(number.checked_mul(multiplier)?)?;
     }
-}
\ No newline at end of file
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{time_str_to_seconds, Config};
+    use crate::libs::load_config::validate_config;
+
+    #[test]
+    fn example_config_loads() {
+        let config: Config = serde_json::from_str(include_str!("../../doc/config.example.json")).unwrap();
+        assert_eq!(validate_config(&config), Ok(Some(604_800)));
+    }
+
+    #[test]
+    fn single_units() {
+        assert_eq!(time_str_to_seconds("30s"), Some(30));
+        assert_eq!(time_str_to_seconds("5min"), Some(300));
+        assert_eq!(time_str_to_seconds("2h"), Some(7_200));
+        assert_eq!(time_str_to_seconds("1d"), Some(86_400));
+        assert_eq!(time_str_to_seconds("1w"), Some(604_800));
+        assert_eq!(time_str_to_seconds("1m"), Some(2_628_000));
+        assert_eq!(time_str_to_seconds("1y"), Some(31_536_000));
+    }
+
+    /// Configs written for the original parser keep their meaning.
+    #[test]
+    fn original_units_are_unchanged() {
+        assert_eq!(time_str_to_seconds("1m"), Some(2_628_000));
+        assert_eq!(time_str_to_seconds("1M"), Some(2_628_000));
+        assert_eq!(time_str_to_seconds("3D"), Some(3 * 86_400));
+        assert_eq!(time_str_to_seconds("2W"), Some(2 * 604_800));
+        assert_eq!(time_str_to_seconds("1Y"), Some(31_536_000));
+        assert_eq!(time_str_to_seconds("2 Months"), Some(2 * 2_628_000));
+    }
+
+    #[test]
+    fn long_unit_names() {
+        assert_eq!(time_str_to_seconds("3days"), Some(3 * 86_400));
+        assert_eq!(time_str_to_seconds("2 weeks"), Some(2 * 604_800));
+        assert_eq!(time_str_to_seconds("1 month"), Some(2_628_000));
+        assert_eq!(time_str_to_seconds("10minutes"), Some(600));
+    }
+
+    #[test]
+    fn compound_durations() {
+        assert_eq!(time_str_to_seconds("1d12h"), Some(129_600));
+        assert_eq!(time_str_to_seconds("1d 12h"), Some(129_600));
+        assert_eq!(time_str_to_seconds(" 1h 30min 15s "), Some(5_415));
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(time_str_to_seconds(""), None);
+        assert_eq!(time_str_to_seconds("   "), None);
+        assert_eq!(time_str_to_seconds("12"), None);
+        assert_eq!(time_str_to_seconds("d"), None);
+        assert_eq!(time_str_to_seconds("1x"), None);
+        assert_eq!(time_str_to_seconds("-1d"), None);
+        assert_eq!(time_str_to_seconds("1.5h"), None);
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert_eq!(time_str_to_seconds("18446744073709551615s"), Some(u64::MAX));
+        assert_eq!(time_str_to_seconds("18446744073709551616s"), None);
+        assert_eq!(time_str_to_seconds("18446744073709551615y"), None);
+        assert_eq!(time_str_to_seconds("18446744073709551615s 1s"), None);
+    }
+}
@@ -1,26 +1,199 @@
 use std::sync::{Arc, Mutex};
-use redis::Client;
+use std::time::Duration;
+use redis::{Client, Commands, Connection, ConnectionLike, RedisResult, Value};
+use super::message::retention::KeyExpiry;
+use super::at_rest::AtRest;
+
+pub use super::live_config::AutoDeleteTime;
 
 pub struct RedisConfig {
     pub(crate) url: String,
-    pub(crate) auto_delete_time: Option<u64>
+    pub(crate) auto_delete_time: AutoDeleteTime,
+    pub(crate) at_rest: AtRest,
+}
+
+/// Idle connections kept for reuse, more are opened while they are all in use.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// A Redis that stopped answering fails the operation instead of holding its thread.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections to Redis shared by every store, so operations don't each open their own.
+#[derive(Clone)]
+pub struct RedisPool {
+    client: Client,
+    idle: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl RedisPool {
+    fn new(client: Client) -> Self {
+        RedisPool { client, idle: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// An idle connection, or a new one. It goes back to the pool when dropped.
+    pub fn get_connection(&self) -> RedisResult<PooledConnection> {
+        let idle = self.idle.lock().unwrap().pop();
+        let con = match idle {
+            Some(con) => con,
+            None => {
+                let con = self.client.get_connection_with_timeout(CONNECT_TIMEOUT)?;
+                con.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+                con.set_write_timeout(Some(COMMAND_TIMEOUT))?;
+                con
+            }
+        };
+        Ok(PooledConnection { con: Some(con), idle: self.idle.clone(), broken: false })
+    }
+
+    /// For connections that are kept apart from the pool, e.g. to subscribe.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+pub struct PooledConnection {
+    con: Option<Connection>,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    /// Set when a reply may still be on its way, the connection can't be reused then.
+    broken: bool,
+}
+
+impl PooledConnection {
+    fn con(&mut self) -> &mut Connection {
+        self.con.as_mut().expect("only taken on drop")
+    }
+
+    fn check<T>(&mut self, result: RedisResult<T>) -> RedisResult<T> {
+        if let Err(e) = &result {
+            if e.is_io_error() || e.is_timeout() || e.is_connection_dropped() {
+                self.broken = true;
+            }
+        }
+        result
+    }
+}
+
+impl ConnectionLike for PooledConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        let result = self.con().req_packed_command(cmd);
+        self.check(result)
+    }
+
+    fn req_packed_commands(&mut self, cmd: &[u8], offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        let result = self.con().req_packed_commands(cmd, offset, count);
+        self.check(result)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.con.as_ref().map_or(0, ConnectionLike::get_db)
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.con().check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.con.as_ref().is_some_and(ConnectionLike::is_open)
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let con = match self.con.take() {
+            Some(con) if !self.broken && con.is_open() => con,
+            _ => return,
+        };
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(con);
+        }
+    }
 }
 
 pub struct RedisConnection {
-    pub client: Arc<Mutex<Client>>,
-    pub auto_delete_time: Option<u64>
+    pub client: RedisPool,
+    pub auto_delete_time: AutoDeleteTime,
+    pub at_rest: AtRest,
 }
 
 impl RedisConnection {
     pub fn new(config: &RedisConfig) -> Result<Self, String> {
         let client = Client::open(config.url.as_str()).map_err(|e| e.to_string())?;
         Ok(Self {
-            client: Arc::new(Mutex::new(client)),
-            auto_delete_time: config.auto_delete_time
+            client: RedisPool::new(client),
+            auto_delete_time: config.auto_delete_time.clone(),
+            at_rest: config.at_rest.clone(),
         })
     }
 
-    pub fn get_client(&self) -> Arc<Mutex<Client>> {
+    pub fn get_client(&self) -> RedisPool {
         self.client.clone()
     }
-}
\ No newline at end of file
+}
+/// Seconds until a key expires, `None` when it never expires or does not exist.
+pub fn key_ttl(con: &mut impl ConnectionLike, key: &str) -> Result<Option<u64>, String> {
+    let ttl: i64 = con.ttl(key).map_err(|e| e.to_string())?;
+    Ok(u64::try_from(ttl).ok())
+}
+
+/// Apply the TTL required by the retention rules to a key that was just written.
+pub fn apply_key_expiry(con: &mut impl ConnectionLike, key: &str, expiry: KeyExpiry) -> Result<(), String> {
+    match expiry {
+        KeyExpiry::ExpireIn(time) => con.expire::<&str, ()>(key, time as usize),
+        KeyExpiry::Persist => con.persist::<&str, ()>(key),
+    }.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::message::retention::key_expiry;
+
+    /// Records the commands sent to it and answers every one with OK.
+    #[derive(Default)]
+    struct RecordingConnection {
+        commands: Vec<String>,
+    }
+
+    impl ConnectionLike for RecordingConnection {
+        fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+            // Keep the arguments of the RESP encoded command, without the length lines.
+            let args: Vec<&str> = std::str::from_utf8(cmd).unwrap()
+                .split("\r\n")
+                .filter(|line| !line.is_empty() && !line.starts_with('*') && !line.starts_with('$'))
+                .collect();
+            self.commands.push(args.join(" "));
+            Ok(Value::Int(1))
+        }
+
+        fn req_packed_commands(&mut self, cmd: &[u8], _offset: usize, _count: usize) -> RedisResult<Vec<Value>> {
+            self.req_packed_command(cmd).map(|value| vec![value])
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            true
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn enabled_auto_delete_issues_expire() {
+        let mut con = RecordingConnection::default();
+        apply_key_expiry(&mut con, "sender:1:line", key_expiry(Some(604_800))).unwrap();
+        assert_eq!(con.commands, ["EXPIRE sender:1:line 604800"]);
+    }
+
+    #[test]
+    fn disabled_auto_delete_issues_persist() {
+        let mut con = RecordingConnection::default();
+        apply_key_expiry(&mut con, "sender:1:line", key_expiry(None)).unwrap();
+        assert_eq!(con.commands, ["PERSIST sender:1:line"]);
+    }
+}
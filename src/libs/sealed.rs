@@ -0,0 +1,51 @@
+//! Sealed sender: delivering to a recipient without telling the server who sends.
+//!
+//! A member of a line asks for its delivery token and hands it to its peers inside
+//! encrypted content. Whoever presents the token with the line may queue a message for
+//! that member without authenticating, so the server only learns the recipient, found
+//! by checking the token against each member of the line. The sender is named
+//! inside the encrypted content alone.
+//!
+//! A token is the HMAC of the line and the recipient under `Sealed Sender Secret`,
+//! so nothing has to be stored, and changing the secret revokes every token.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use super::at_rest::{from_hex, to_hex};
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &str, line_id: u16, recipient: &str) -> HmacSha256 {
+    // HMAC takes keys of any length.
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("delivery:{}:{}", line_id, recipient).as_bytes());
+    mac
+}
+
+pub fn delivery_token(secret: &str, line_id: u16, recipient: &str) -> String {
+    to_hex(&mac(secret, line_id, recipient).finalize().into_bytes())
+}
+
+/// Compares in constant time.
+pub fn verify_delivery_token(secret: &str, line_id: u16, recipient: &str, token: &str) -> bool {
+    match from_hex(token) {
+        Some(token) => mac(secret, line_id, recipient).verify_slice(&token).is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_bound_to_line_and_recipient() {
+        let token = delivery_token("secret", 7, "alice");
+        assert!(verify_delivery_token("secret", 7, "alice", &token));
+        assert!(!verify_delivery_token("secret", 8, "alice", &token));
+        assert!(!verify_delivery_token("secret", 7, "bob", &token));
+        assert!(!verify_delivery_token("other secret", 7, "alice", &token));
+        assert!(!verify_delivery_token("secret", 7, "alice", "not hex"));
+        assert!(!verify_delivery_token("secret", 7, "alice", ""));
+    }
+}
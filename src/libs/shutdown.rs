@@ -0,0 +1,50 @@
+use std::time::Duration;
+use actix_web::dev::ServerHandle;
+use actix_web::{rt, web};
+use tracing::{info, error};
+
+use super::core::SharedCore;
+
+/// Time given to sessions to flush their close frames before the server stops accepting work.
+const CLOSE_FRAME_GRACE: Duration = Duration::from_millis(500);
+
+/// Drain sessions on SIGTERM or Ctrl-C, then stop the server gracefully.
+/// The server itself bounds the stop by its shutdown timeout.
+pub fn handle_shutdown_signals(core: SharedCore, server: ServerHandle) {
+    #[cfg(unix)]
+    {
+        let core = core.clone();
+        let server = server.clone();
+        rt::spawn(async move {
+            use rt::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    if terminate.recv().await.is_some() {
+                        info!("SIGTERM received, shutting down");
+                        shutdown(core, server).await;
+                    }
+                }
+                Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+            }
+        });
+    }
+
+    rt::spawn(async move {
+        match rt::signal::ctrl_c().await {
+            Ok(()) => {
+                info!("Ctrl-C received, shutting down");
+                shutdown(core, server).await;
+            }
+            Err(e) => error!("Failed to listen for Ctrl-C: {}", e),
+        }
+    });
+}
+
+async fn shutdown(core: SharedCore, server: ServerHandle) {
+    // Withdrawing the sessions from the cluster waits on storage.
+    if let Err(e) = web::block(move || core.start_draining()).await {
+        error!("Failed to drain sessions: {}", e);
+    }
+    rt::time::sleep(CLOSE_FRAME_GRACE).await;
+    server.stop(true).await;
+}
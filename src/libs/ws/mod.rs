@@ -2,3 +2,9 @@ pub mod parse_request;
 pub mod ws_response;
 pub mod ping;
 pub mod ws_sent_message;
+pub mod protocol;
+
+/// Versions of the WebSocket protocol this server speaks, see `protocol`.
+pub const PROTOCOL_VERSIONS: &[&str] = &[protocol::PCP_V1, protocol::PCP_V2];
+/// Encodings of WebSocket frames this server accepts.
+pub const ENCODINGS: &[&str] = &["json"];
@@ -1,13 +1,40 @@
 use serde_derive::{Deserialize, Serialize};
 use crate::libs::message::Message;
+use crate::libs::message::signal::{Signal, SignalKind};
+
+const MISSING_CONTENT: &str = "missing field `content`";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WsRequest {
     sender: String,
     line_id: u16,
-    content: String,
+    /// Required by messages, optional for signals and unused by acknowledgements.
+    #[serde(default)]
+    content: Option<String>,
+    /// Makes the request an ephemeral signal instead of a message.
+    #[serde(default)]
+    signal: Option<SignalKind>,
+    /// Makes the request an acknowledgement of every queued message up to this sequence number.
+    #[serde(default)]
+    ack: Option<u64>,
+    #[serde(default)]
+    ttl: Option<u64>,
+    #[serde(default)]
+    burn_after_reading: bool,
+    /// Chosen by the client to match the response to the request.
+    #[serde(default)]
+    request_id: Option<String>,
 }
 
+/// What a client asks the server to do, whichever protocol version it speaks.
+pub enum ClientRequest {
+    /// Opens a `pcp.v2` session, answered with a `Welcome`.
+    Hello { client: Option<String> },
+    SendMessage(Message),
+    SendSignal(Signal),
+    /// The device received the queued messages up to `have_up_to`, which are not replayed to it again.
+    Acknowledge { sender: String, line_id: u16, have_up_to: u64 },
+}
 
 impl WsRequest {
     pub fn parse_request(request: &str) -> Result<Self, String> {
@@ -16,11 +43,20 @@ impl WsRequest {
             Err(e) => Err(e.to_string()),
         }
     }
-    pub fn to_message(self) -> Message {
-        Message {
-            sender: self.sender,
-            line_id: self.line_id,
-            content: self.content,
-        }
+    pub fn into_request(self) -> Result<(Option<String>, ClientRequest), String> {
+        let WsRequest { sender, line_id, content, signal, ack, ttl, burn_after_reading, request_id } = self;
+        let request = match (ack, signal) {
+            (Some(have_up_to), _) => ClientRequest::Acknowledge { sender, line_id, have_up_to },
+            (None, Some(kind)) => ClientRequest::SendSignal(Signal { line_id, sender, kind, content: content.unwrap_or_default() }),
+            (None, None) => ClientRequest::SendMessage(Message {
+                sender,
+                line_id,
+                content: content.ok_or(MISSING_CONTENT)?,
+                ttl,
+                burn_after_reading,
+                seq: None,
+            }),
+        };
+        Ok((request_id, request))
     }
-}
\ No newline at end of file
+}
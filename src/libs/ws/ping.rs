@@ -1,6 +1,7 @@
 use actix::Message;
 use serde_derive::{Deserialize, Serialize};
 
+#[allow(dead_code)]
 #[derive(Message, Serialize, Deserialize, Debug, Clone)]
 #[rtype(result = "()")]
 pub struct Ping;
\ No newline at end of file
@@ -0,0 +1,164 @@
+//! Versions of the WebSocket protocol, chosen through `Sec-WebSocket-Protocol`.
+//!
+//! - `pcp.v1` is the original protocol: every request is a flat `WsRequest` that names its sender.
+//!   Clients that ask for no subprotocol speak it too.
+//! - `pcp.v2` tags every request with a `type`, takes the sender from the session,
+//!   and lets the client open with a `hello` that the server answers with a `Welcome`.
+//!
+//! Both versions are parsed into the same `ClientRequest`, so the rest of the server
+//! does not know which one a client speaks.
+
+use serde_derive::Deserialize;
+use super::parse_request::{ClientRequest, WsRequest};
+use crate::libs::message::Message;
+use crate::libs::message::signal::{Signal, SignalKind};
+
+pub const PCP_V1: &str = "pcp.v1";
+pub const PCP_V2: &str = "pcp.v2";
+
+/// What this server supports beyond plain messages, listed in a `Welcome`.
+pub const FEATURES: &[&str] = &["groups", "signals", "calls", "blobs", "resume", "multi_device"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    V1,
+    V2,
+}
+
+impl Protocol {
+    /// Value of `Sec-WebSocket-Protocol` naming the version.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::V1 => PCP_V1,
+            Protocol::V2 => PCP_V2,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            PCP_V1 => Some(Protocol::V1),
+            PCP_V2 => Some(Protocol::V2),
+            _ => None,
+        }
+    }
+
+    /// Pick the first version the client asks for that the server speaks.
+    /// A client asking for none speaks `pcp.v1`, one asking only for unknown versions gets `None`.
+    pub fn negotiate(requested: Option<&str>) -> Option<Self> {
+        let requested = match requested {
+            Some(requested) if !requested.trim().is_empty() => requested,
+            _ => return Some(Protocol::V1),
+        };
+        requested.split(',').find_map(|name| Protocol::from_name(name.trim()))
+    }
+
+    /// `sender` is who the session connected as, which `pcp.v2` requests do not repeat.
+    /// Returns the `request_id` the client chose along with the request.
+    pub fn parse_request(&self, text: &str, sender: &str) -> Result<(Option<String>, ClientRequest), String> {
+        match self {
+            Protocol::V1 => WsRequest::parse_request(text).and_then(WsRequest::into_request),
+            Protocol::V2 => serde_json::from_str::<TaggedRequest>(text)
+                .map(|tagged| (tagged.request_id, tagged.request.into_request(sender)))
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TaggedRequest {
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(flatten)]
+    request: WsRequestV2,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsRequestV2 {
+    Hello {
+        /// Name and version of the client, only logged.
+        #[serde(default)]
+        client: Option<String>,
+    },
+    Message {
+        line_id: u16,
+        content: String,
+        #[serde(default)]
+        ttl: Option<u64>,
+        #[serde(default)]
+        burn_after_reading: bool,
+    },
+    Signal {
+        line_id: u16,
+        kind: SignalKind,
+        #[serde(default)]
+        content: String,
+    },
+    Ack {
+        line_id: u16,
+        have_up_to: u64,
+    },
+}
+
+impl WsRequestV2 {
+    fn into_request(self, sender: &str) -> ClientRequest {
+        let sender = sender.to_string();
+        match self {
+            WsRequestV2::Hello { client } => ClientRequest::Hello { client },
+            WsRequestV2::Message { line_id, content, ttl, burn_after_reading } => ClientRequest::SendMessage(Message {
+                line_id,
+                sender,
+                content,
+                ttl,
+                burn_after_reading,
+                seq: None,
+            }),
+            WsRequestV2::Signal { line_id, kind, content } => ClientRequest::SendSignal(Signal { line_id, sender, kind, content }),
+            WsRequestV2::Ack { line_id, have_up_to } => ClientRequest::Acknowledge { sender, line_id, have_up_to },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(Protocol::negotiate(None), Some(Protocol::V1));
+        assert_eq!(Protocol::negotiate(Some("pcp.v2, pcp.v1")), Some(Protocol::V2));
+        assert_eq!(Protocol::negotiate(Some("chat, pcp.v1")), Some(Protocol::V1));
+        assert_eq!(Protocol::negotiate(Some("pcp.v3")), None);
+    }
+
+    #[test]
+    fn both_versions_parse_to_the_same_request() {
+        let legacy = r#"{"sender": "a", "line_id": 7, "content": "hi"}"#;
+        let tagged = r#"{"type": "message", "line_id": 7, "content": "hi"}"#;
+        for request in [Protocol::V1.parse_request(legacy, "a"), Protocol::V2.parse_request(tagged, "a")] {
+            match request {
+                Ok((None, ClientRequest::SendMessage(message))) => {
+                    assert_eq!((message.sender.as_str(), message.line_id, message.content.as_str()), ("a", 7, "hi"));
+                }
+                _ => panic!("expected a message"),
+            }
+        }
+        assert!(Protocol::V2.parse_request(legacy, "a").is_err());
+    }
+
+    #[test]
+    fn messages_need_content() {
+        assert!(Protocol::V1.parse_request(r#"{"sender": "a", "line_id": 7}"#, "a").is_err());
+        assert!(Protocol::V2.parse_request(r#"{"type": "message", "line_id": 7}"#, "a").is_err());
+        let typing = r#"{"sender": "a", "line_id": 7, "signal": "typing"}"#;
+        assert!(matches!(Protocol::V1.parse_request(typing, "a"), Ok((None, ClientRequest::SendSignal(_)))));
+    }
+
+    #[test]
+    fn request_ids_are_returned() {
+        let legacy = r#"{"sender": "a", "line_id": 7, "ack": 3, "request_id": "1"}"#;
+        let tagged = r#"{"type": "ack", "line_id": 7, "have_up_to": 3, "request_id": "2"}"#;
+        assert!(matches!(Protocol::V1.parse_request(legacy, "a"), Ok((Some(id), ClientRequest::Acknowledge { .. })) if id == "1"));
+        assert!(matches!(Protocol::V2.parse_request(tagged, "a"), Ok((Some(id), ClientRequest::Acknowledge { .. })) if id == "2"));
+    }
+}
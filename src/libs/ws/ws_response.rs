@@ -1,26 +1,111 @@
 use serde_derive::{Deserialize, Serialize};
+use crate::libs::core::{
+    BehaviorAfterReceiveMessage,
+    INTERNAL_SERVER_ERROR, ILLEGAL_INPUT, SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN, MESSAGE_TOO_LARGE,
+    KICKED_FROM_LINE, SERVER_RESTARTING, PEER_OFFLINE, CALLS_NEED_TWO_MEMBERS,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WsResponseCode {
     Success,
     Error,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What became of a message or signal the server accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    /// Handed to a session of the recipient.
+    Live,
+    /// Kept in a queue until the recipient fetches it.
+    Queued,
+    /// A signal nobody was online for.
+    Dropped,
+    /// A message sent to a group nobody else is in.
+    NoRecipient,
+}
+
+impl From<BehaviorAfterReceiveMessage> for Delivery {
+    fn from(behavior: BehaviorAfterReceiveMessage) -> Self {
+        match behavior {
+            BehaviorAfterReceiveMessage::SendToAnotherSender => Delivery::Live,
+            BehaviorAfterReceiveMessage::PushedToQueue => Delivery::Queued,
+            BehaviorAfterReceiveMessage::NoRecipient => Delivery::NoRecipient,
+        }
+    }
+}
+
+/// Lets a client react to an error without matching on its message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WsErrorKind {
+    /// The request could not be parsed.
+    InvalidRequest,
+    IllegalInput,
+    NotInLine,
+    TooLarge,
+    PeerOffline,
+    Unsupported,
+    Forbidden,
+    Unavailable,
+    Internal,
+    Other,
+}
+
+impl WsErrorKind {
+    /// Classify an error returned by `Core`.
+    pub fn of(e: &str) -> Self {
+        match e {
+            ILLEGAL_INPUT => WsErrorKind::IllegalInput,
+            SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN => WsErrorKind::NotInLine,
+            MESSAGE_TOO_LARGE => WsErrorKind::TooLarge,
+            PEER_OFFLINE => WsErrorKind::PeerOffline,
+            CALLS_NEED_TWO_MEMBERS => WsErrorKind::Unsupported,
+            KICKED_FROM_LINE => WsErrorKind::Forbidden,
+            SERVER_RESTARTING => WsErrorKind::Unavailable,
+            INTERNAL_SERVER_ERROR => WsErrorKind::Internal,
+            _ => WsErrorKind::Other,
+        }
+    }
+}
+
+/// The result of a client request, correlated by the `request_id` the client chose.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WsResponse {
+    #[serde(default)]
+    pub request_id: Option<String>,
     pub code: WsResponseCode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery: Option<Delivery>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<WsErrorKind>,
     pub error_message: Option<String>,
 }
 
-pub fn get_send_response(result: Result<(),String>) -> String {
-    serde_json::to_string(&WsResponse {
-        code: match result {
-            Ok(_) => WsResponseCode::Success,
-            Err(_) => WsResponseCode::Error,
-        },
-        error_message: match result {
-            Ok(_) => None,
-            Err(e) => Some(e),
-        },
-    }).unwrap()
-}
\ No newline at end of file
+impl WsResponse {
+    pub fn success(request_id: Option<String>, delivery: Option<Delivery>) -> Self {
+        WsResponse {
+            request_id,
+            code: WsResponseCode::Success,
+            delivery,
+            error: None,
+            error_message: None,
+        }
+    }
+
+    pub fn failure(request_id: Option<String>, error: WsErrorKind, message: String) -> Self {
+        WsResponse {
+            request_id,
+            code: WsResponseCode::Error,
+            delivery: None,
+            error: Some(error),
+            error_message: Some(message),
+        }
+    }
+}
+
+/// The `request_id` of a request that could not be parsed, if it has one at all.
+pub fn request_id_of(text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    Some(value.get("request_id")?.as_str()?.to_string())
+}
@@ -1,11 +1,52 @@
 use actix::Message;
 use serde_derive::{Deserialize, Serialize};
 use crate::libs::message::Message as ChatMessage;
+use crate::libs::message::signal::Signal;
+use super::ws_response::WsResponse;
 
 #[derive(Message)]
 #[rtype(result = "()")]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
     PushChatMessages(Vec<ChatMessage>),
-    Error(String)
+    /// An ephemeral signal from a peer, see `signal`.
+    Signal(Signal),
+    Error(String),
+    /// The result of a client request.
+    Response(WsResponse),
+    /// Another sender joined a line for the first time.
+    /// In peer events, `peer` is the member's label in the line, never its identifier.
+    PeerJoined { line_id: u16, peer: String },
+    /// A member left a line, or was removed from it.
+    PeerLeft { line_id: u16, peer: String },
+    /// A member of a line connected to it.
+    PeerOnline { line_id: u16, peer: String },
+    /// The last session of a member in a line closed.
+    PeerOffline { line_id: u16, peer: String },
+    /// The line expires in `expires_in` seconds unless it is used before.
+    LineExpiringSoon { line_id: u16, expires_in: u64 },
+    /// An offer in the line was neither answered nor hung up in time.
+    CallTimedOut { line_id: u16 },
+    /// Answer to a hello, with what the server supports.
+    Welcome { protocol: String, server_version: String, features: Vec<String>, limits: Limits },
+    /// Close the session. Never sent as a text frame.
+    #[serde(skip)]
+    Disconnect(DisconnectReason),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Limits {
+    pub max_message_size: usize,
+    pub max_group_members: usize,
+    pub max_blob_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    EvictedByAdmin,
+    /// Kicked from a group by its admin.
+    Kicked,
+    ServerRestarting,
+    /// The sender left the line, from this or another device.
+    LeftLine,
 }
@@ -3,15 +3,75 @@ mod route;
 mod actors;
 
 use actix_web::{App, HttpServer, web};
-use route::chat;
+use libs::core::{Core, watch_blob_retention, watch_call_timeouts, watch_line_count, watch_line_expiry, watch_line_indexes};
+use libs::live_config::{LiveConfig, watch_config};
+use libs::logging::init_logging;
+use libs::parse_config::time_str_to_seconds;
+use libs::shutdown::handle_shutdown_signals;
+use libs::load_config::load_config;
+use route::{admin, blobs, chat, groups, health, lines, messages, metrics, profile, push, sealed};
+
+fn config_error((e, detail): (String, String)) -> std::io::Error {
+    std::io::Error::other(format!("{} {}", e, detail))
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
+    let live_config = web::Data::new(LiveConfig::load().map_err(config_error)?);
+    init_logging(&live_config.current().log).map_err(std::io::Error::other)?;
+    watch_config(live_config.clone().into_inner());
+
+    let mut loaded = load_config(live_config.clone().into_inner()).map_err(config_error)?;
+    let cluster_listener = loaded.cluster_listener.take();
+    let storage_probe = web::Data::new(loaded.storage_probe.take().expect("set by load_config"));
+    let core = web::Data::new(Core::new(loaded));
+    // Already validated, see `validate_config`.
+    let shutdown_timeout = time_str_to_seconds(&live_config.current().config.shutdown_timeout).unwrap_or(30);
+    let shared_core = core.clone().into_inner();
+    if let Some(listener) = cluster_listener {
+        listener.spawn(shared_core.clone());
+    }
+    watch_line_expiry(shared_core.clone());
+    watch_call_timeouts(shared_core.clone());
+    watch_blob_retention(shared_core.clone());
+    watch_line_count(shared_core.clone());
+    watch_line_indexes(shared_core.clone());
+
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(live_config.clone())
+            .app_data(core.clone())
+            .app_data(storage_probe.clone())
+            .app_data(web::PayloadConfig::new(blobs::MAX_CHUNK_SIZE))
+            .service(profile::get_profile)
+            .service(lines::list_lines)
+            .service(lines::leave_line)
+            .service(messages::fetch_messages)
+            .service(messages::ack_messages)
+            .service(messages::send_message)
+            .service(groups::kick_member)
+            .service(groups::invite_member)
+            .service(blobs::start_upload)
+            .service(blobs::upload_chunk)
+            .service(blobs::complete_upload)
+            .service(blobs::fetch_blob)
+            .service(push::register_endpoint)
+            .service(push::unregister_endpoint)
+            .service(sealed::get_delivery_token)
+            .service(sealed::send_sealed)
+            .service(admin::list_lines)
+            .service(admin::get_line)
+            .service(admin::evict_sender)
+            .service(admin::purge_line)
+            .service(metrics::get_metrics)
+            .service(health::healthz)
+            .service(health::readyz)
             .route("/ws/", web::get().to(chat::chat_route))
     })
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout)
         .bind("127.0.0.1:8080")?
-        .run()
-        .await
+        .run();
+    handle_shutdown_signals(shared_core, server.handle());
+    server.await
 }
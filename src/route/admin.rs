@@ -0,0 +1,54 @@
+use actix_web::{delete, get, web, Error, HttpRequest, HttpResponse};
+use crate::libs::core::{Core, LineDetails, LineSummary};
+use crate::libs::live_config::LiveConfig;
+use super::auth::authenticate_admin;
+use super::blocking::with_core;
+
+#[get("/admin/lines")]
+pub async fn list_lines(
+    req: HttpRequest,
+    live_config: web::Data<LiveConfig>,
+    core: web::Data<Core>,
+) -> Result<web::Json<Vec<LineSummary>>, Error> {
+    authenticate_admin(&req, &live_config)?;
+    let lines = with_core(&core, move |core| core.list_lines()).await?;
+    Ok(web::Json(lines))
+}
+
+#[get("/admin/lines/{line_id}")]
+pub async fn get_line(
+    req: HttpRequest,
+    line_id: web::Path<u16>,
+    live_config: web::Data<LiveConfig>,
+    core: web::Data<Core>,
+) -> Result<web::Json<LineDetails>, Error> {
+    authenticate_admin(&req, &live_config)?;
+    let details = with_core(&core, move |core| core.line_details(*line_id)).await?;
+    Ok(web::Json(details))
+}
+
+/// `member` is the label `get_line` lists the member under.
+#[delete("/admin/lines/{line_id}/senders/{member}")]
+pub async fn evict_sender(
+    req: HttpRequest,
+    path: web::Path<(u16, String)>,
+    live_config: web::Data<LiveConfig>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    authenticate_admin(&req, &live_config)?;
+    let (line_id, member) = path.into_inner();
+    with_core(&core, move |core| core.evict_sender(member, line_id)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/admin/lines/{line_id}/queues")]
+pub async fn purge_line(
+    req: HttpRequest,
+    line_id: web::Path<u16>,
+    live_config: web::Data<LiveConfig>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    authenticate_admin(&req, &live_config)?;
+    with_core(&core, move |core| core.purge_line(*line_id)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,42 @@
+use actix_web::{Error, HttpRequest, error::{ErrorNotFound, ErrorUnauthorized}, http::header::AUTHORIZATION};
+use crate::libs::core::{Sender, string_to_sender};
+use crate::libs::live_config::LiveConfig;
+
+const MISSING_CREDENTIALS: &str = "Missing bearer token.";
+const INVALID_CREDENTIALS: &str = "Invalid bearer token.";
+const ADMIN_API_DISABLED: &str = "Admin API is disabled.";
+
+fn bearer_token(req: &HttpRequest) -> Result<&str, Error> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| ErrorUnauthorized(MISSING_CREDENTIALS))
+}
+
+/// HTTP clients authenticate as a sender by presenting its identifier as a bearer token,
+/// the same secret that lets a WebSocket client join a line.
+pub fn authenticated_sender(req: &HttpRequest) -> Result<Sender, Error> {
+    let token = bearer_token(req)?;
+    string_to_sender(token.to_string()).map_err(ErrorUnauthorized)
+}
+
+/// The admin API is only reachable with the `Admin Token` from the config.
+pub fn authenticate_admin(req: &HttpRequest, live_config: &LiveConfig) -> Result<(), Error> {
+    let config = live_config.current();
+    let admin_token = match &config.config.admin_token {
+        Some(admin_token) if !admin_token.is_empty() => admin_token,
+        _ => return Err(ErrorNotFound(ADMIN_API_DISABLED)),
+    };
+    let token = bearer_token(req)?;
+    if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(ErrorUnauthorized(INVALID_CREDENTIALS))
+    }
+}
+
+/// Compare without returning early, so the time taken does not leak the token.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
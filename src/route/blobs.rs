@@ -0,0 +1,118 @@
+use actix_web::{get, patch, post, web, Error, HttpRequest, HttpResponse, error::ErrorNotFound};
+use futures_util::stream;
+use serde_derive::{Deserialize, Serialize};
+use tracing::error;
+use crate::libs::blob::{is_valid_id, BLOB_ID_LEN, UPLOAD_ID_LEN};
+use crate::libs::core::{Core, BLOB_NOT_FOUND, UPLOAD_NOT_FOUND};
+use super::auth::authenticated_sender;
+use super::blocking::with_core;
+
+/// Largest chunk of an upload, larger blobs are uploaded in several chunks.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Serialize)]
+pub struct UploadStarted {
+    upload_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChunkQuery {
+    /// Size uploaded so far, where the chunk goes.
+    offset: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadProgress {
+    size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlobCreated {
+    blob_id: String,
+    size: u64,
+}
+
+fn check_upload_id(upload_id: &str) -> Result<(), Error> {
+    if !is_valid_id(upload_id, UPLOAD_ID_LEN) {
+        return Err(ErrorNotFound(UPLOAD_NOT_FOUND));
+    }
+    Ok(())
+}
+
+#[post("/lines/{line_id}/blobs/uploads")]
+pub async fn start_upload(
+    req: HttpRequest,
+    line_id: web::Path<u16>,
+    core: web::Data<Core>,
+) -> Result<web::Json<UploadStarted>, Error> {
+    let sender = authenticated_sender(&req)?;
+    let upload_id = with_core(&core, move |core| core.start_upload(sender, *line_id)).await?;
+    Ok(web::Json(UploadStarted { upload_id }))
+}
+
+/// Append the body, already encrypted by the client, to an upload.
+#[patch("/lines/{line_id}/blobs/uploads/{upload_id}")]
+pub async fn upload_chunk(
+    req: HttpRequest,
+    path: web::Path<(u16, String)>,
+    query: web::Query<ChunkQuery>,
+    body: web::Bytes,
+    core: web::Data<Core>,
+) -> Result<web::Json<UploadProgress>, Error> {
+    let sender = authenticated_sender(&req)?;
+    check_upload_id(&path.1)?;
+    let size = with_core(&core, move |core| core.append_upload(sender, path.0, &path.1, query.offset, &body)).await?;
+    Ok(web::Json(UploadProgress { size }))
+}
+
+#[post("/lines/{line_id}/blobs/uploads/{upload_id}/complete")]
+pub async fn complete_upload(
+    req: HttpRequest,
+    path: web::Path<(u16, String)>,
+    core: web::Data<Core>,
+) -> Result<web::Json<BlobCreated>, Error> {
+    let sender = authenticated_sender(&req)?;
+    check_upload_id(&path.1)?;
+    let (blob_id, size) = with_core(&core, move |core| core.complete_upload(sender, path.0, &path.1)).await?;
+    Ok(web::Json(BlobCreated { blob_id, size }))
+}
+
+/// Stream a blob, read in parts from blocking threads.
+#[get("/lines/{line_id}/blobs/{blob_id}")]
+pub async fn fetch_blob(
+    req: HttpRequest,
+    path: web::Path<(u16, String)>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    let sender = authenticated_sender(&req)?;
+    let (line_id, blob_id) = path.into_inner();
+    if !is_valid_id(&blob_id, BLOB_ID_LEN) {
+        return Err(ErrorNotFound(BLOB_NOT_FOUND));
+    }
+    let size = {
+        let blob_id = blob_id.clone();
+        with_core(&core, move |core| core.fetch_blob(sender, line_id, &blob_id)).await?
+    };
+    let chunks = stream::unfold(Some(0), move |offset: Option<u64>| {
+        let core = core.clone();
+        let blob_id = blob_id.clone();
+        async move {
+            let offset = offset?;
+            if offset >= size {
+                // Only a blob sent in full counts as fetched.
+                if let Err(e) = with_core(&core, move |core| core.blob_fetched(sender, line_id, &blob_id)).await {
+                    error!("Failed to record blob fetched: {}", e);
+                }
+                return None;
+            }
+            match with_core(&core, move |core| core.read_blob(line_id, &blob_id, offset)).await {
+                Ok(chunk) => {
+                    let next = offset + chunk.len() as u64;
+                    Some((Ok(web::Bytes::from(chunk)), Some(next)))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        }
+    });
+    Ok(HttpResponse::Ok().content_type("application/octet-stream").no_chunking(size).streaming(chunks))
+}
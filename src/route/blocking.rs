@@ -0,0 +1,13 @@
+use actix_web::{web, Error, error::ErrorInternalServerError};
+use crate::libs::core::Core;
+use super::error::core_error;
+
+/// Call `Core` from a blocking thread, since it waits on storage, and map its error.
+pub async fn with_core<T, F>(core: &web::Data<Core>, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&Core) -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    let core = core.clone();
+    web::block(move || f(&core)).await.map_err(ErrorInternalServerError)?.map_err(core_error)
+}
@@ -1,11 +1,43 @@
-use actix_web::{Error, HttpRequest, HttpResponse, web};
+use actix_web::{Error, HttpRequest, HttpResponse, web, error::{ErrorBadRequest, ErrorServiceUnavailable}, http::header::SEC_WEBSOCKET_PROTOCOL};
 use actix_web_actors::ws;
+use serde_derive::Deserialize;
 use crate::actors::chat_session::WsChatSession;
+use crate::libs::core::{Core, string_to_sender, validate_device, DEFAULT_DEVICE, SERVER_RESTARTING};
+use crate::libs::ws::protocol::Protocol;
+
+const UNSUPPORTED_PROTOCOL: &str = "None of the requested protocol versions is supported.";
+
+#[derive(Debug, Deserialize)]
+pub struct JoinQuery {
+    sender: String,
+    line_id: u16,
+    /// Names the device, so each one receives the queued messages on its own.
+    device: Option<String>,
+    /// Opens the line as a group of up to this many members, if nobody is in it yet.
+    max_members: Option<usize>,
+    /// Sequence number of the last queued message the device received before reconnecting.
+    have_up_to: Option<u64>,
+}
 
 pub async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
+    query: web::Query<JoinQuery>,
+    core: web::Data<Core>,
 ) -> Result<HttpResponse, Error> {
-    let resp = ws::start(WsChatSession { name: None, core: (), user_id: () }, &req, stream);
-    resp
+    let JoinQuery { sender, line_id, device, max_members, have_up_to } = query.into_inner();
+    let sender = string_to_sender(sender).map_err(ErrorBadRequest)?;
+    let device = device.unwrap_or_else(|| DEFAULT_DEVICE.to_string());
+    validate_device(&device).map_err(ErrorBadRequest)?;
+    if core.is_draining() {
+        return Err(ErrorServiceUnavailable(SERVER_RESTARTING));
+    }
+    let requested = req.headers().get(SEC_WEBSOCKET_PROTOCOL).and_then(|value| value.to_str().ok());
+    let protocol = Protocol::negotiate(requested).ok_or_else(|| ErrorBadRequest(UNSUPPORTED_PROTOCOL))?;
+    let session = WsChatSession::new(sender, device, line_id, max_members, have_up_to, protocol, core.into_inner());
+    // Only echoed to clients that asked for a version.
+    let protocols = [protocol.name()];
+    ws::WsResponseBuilder::new(session, &req, stream)
+        .protocols(&protocols)
+        .start()
 }
@@ -0,0 +1,18 @@
+use actix_web::{Error, error::{ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorInternalServerError, ErrorNotFound, ErrorPayloadTooLarge}};
+use crate::libs::core::{
+    INTERNAL_SERVER_ERROR, SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN, NOT_GROUP_ADMIN, KICKED_FROM_LINE,
+    BLOB_NOT_FOUND, UPLOAD_NOT_FOUND, BLOB_TOO_LARGE, UPLOAD_OFFSET_MISMATCH, NOTIFICATIONS_DISABLED,
+    SEALED_SENDER_DISABLED, INVALID_DELIVERY_TOKEN,
+};
+
+/// Map an error returned by `Core` to an HTTP error.
+pub fn core_error(e: String) -> Error {
+    match e.as_str() {
+        SENDING_TO_LINE_THAT_YOU_ARE_NOT_IN | NOT_GROUP_ADMIN | KICKED_FROM_LINE | INVALID_DELIVERY_TOKEN => ErrorForbidden(e),
+        BLOB_NOT_FOUND | UPLOAD_NOT_FOUND | NOTIFICATIONS_DISABLED | SEALED_SENDER_DISABLED => ErrorNotFound(e),
+        BLOB_TOO_LARGE => ErrorPayloadTooLarge(e),
+        UPLOAD_OFFSET_MISMATCH => ErrorConflict(e),
+        INTERNAL_SERVER_ERROR => ErrorInternalServerError(e),
+        _ => ErrorBadRequest(e),
+    }
+}
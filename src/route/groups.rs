@@ -0,0 +1,32 @@
+use actix_web::{delete, post, web, Error, HttpRequest, HttpResponse};
+use crate::libs::core::Core;
+use super::auth::authenticated_sender;
+use super::blocking::with_core;
+
+/// Remove a member from a group. Only its admin may do this.
+/// `member` is the label members are known by in the line, as in the peer events.
+#[delete("/lines/{line_id}/members/{member}")]
+pub async fn kick_member(
+    req: HttpRequest,
+    path: web::Path<(u16, String)>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    let admin = authenticated_sender(&req)?;
+    let (line_id, member) = path.into_inner();
+    with_core(&core, move |core| core.kick_member(admin, line_id, member)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Allow a sender to join a group again after being kicked. Only its admin may do this.
+/// `member` is the label the sender was known by in the line.
+#[post("/lines/{line_id}/members/{member}/invite")]
+pub async fn invite_member(
+    req: HttpRequest,
+    path: web::Path<(u16, String)>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    let admin = authenticated_sender(&req)?;
+    let (line_id, member) = path.into_inner();
+    with_core(&core, move |core| core.invite_member(admin, line_id, member)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
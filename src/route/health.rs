@@ -0,0 +1,44 @@
+use actix_web::{get, web, HttpResponse};
+use serde_derive::Serialize;
+use tracing::warn;
+use crate::libs::core::Core;
+use crate::libs::load_config::StorageProbe;
+
+const STORAGE_UNAVAILABLE: &str = "Storage is unavailable.";
+const SHUTTING_DOWN: &str = "Server is shutting down.";
+
+#[derive(Debug, Serialize)]
+pub struct HealthStatus {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+}
+
+/// The process is alive and serving HTTP.
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(HealthStatus { status: "ok", reason: None })
+}
+
+/// The instance can take traffic, i.e. its storage backend answers.
+/// Storage is pinged through its own handle, so a busy `Core` can't hold up the probe.
+#[get("/readyz")]
+pub async fn readyz(core: web::Data<Core>, storage_probe: web::Data<StorageProbe>) -> HttpResponse {
+    if core.is_draining() {
+        return HttpResponse::ServiceUnavailable().json(HealthStatus {
+            status: "unavailable",
+            reason: Some(SHUTTING_DOWN),
+        });
+    }
+    let checked = web::block(move || storage_probe.ping()).await;
+    match checked.map_err(|e| e.to_string()).and_then(|checked| checked) {
+        Ok(()) => HttpResponse::Ok().json(HealthStatus { status: "ready", reason: None }),
+        Err(e) => {
+            warn!("Readiness check failed: {}", e);
+            HttpResponse::ServiceUnavailable().json(HealthStatus {
+                status: "unavailable",
+                reason: Some(STORAGE_UNAVAILABLE),
+            })
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use actix_web::{delete, get, web, Error, HttpRequest, HttpResponse};
+use serde_derive::Deserialize;
+use crate::libs::core::{Core, MemberLine, DEFAULT_DEVICE};
+use super::auth::authenticated_sender;
+use super::blocking::with_core;
+
+#[derive(Debug, Deserialize)]
+pub struct LinesQuery {
+    /// Whose acknowledgements `queued` counts from.
+    #[serde(default = "default_device")]
+    device: String,
+}
+
+fn default_device() -> String {
+    DEFAULT_DEVICE.to_string()
+}
+
+/// Every line the caller is a member of, with what is waiting in each.
+#[get("/lines")]
+pub async fn list_lines(
+    req: HttpRequest,
+    query: web::Query<LinesQuery>,
+    core: web::Data<Core>,
+) -> Result<web::Json<Vec<MemberLine>>, Error> {
+    let sender = authenticated_sender(&req)?;
+    let lines = with_core(&core, move |core| core.lines_of(sender, &query.device)).await?;
+    Ok(web::Json(lines))
+}
+
+/// Leave a line for good. The other members are told, and the caller's sessions in it are closed.
+#[delete("/lines/{line_id}")]
+pub async fn leave_line(
+    req: HttpRequest,
+    line_id: web::Path<u16>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    let sender = authenticated_sender(&req)?;
+    let line_id = line_id.into_inner();
+    with_core(&core, move |core| core.exit_line(sender, line_id)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
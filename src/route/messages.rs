@@ -0,0 +1,100 @@
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use serde_derive::{Deserialize, Serialize};
+use crate::libs::core::{Core, sender_to_string, DEFAULT_DEVICE};
+use crate::libs::ws::ws_response::Delivery;
+use crate::libs::message::Message;
+use crate::libs::message::queue_trait::QueuedMessages;
+use super::auth::authenticated_sender;
+use super::blocking::with_core;
+use super::error::core_error;
+
+const DEFAULT_FETCH_LIMIT: usize = 100;
+const MAX_FETCH_LIMIT: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct FetchQuery {
+    /// Defaults to the first message the device has not acknowledged.
+    cursor: Option<u64>,
+    limit: Option<usize>,
+    #[serde(default = "default_device")]
+    device: String,
+    /// Read the sealed messages instead, see `crate::libs::sealed`.
+    #[serde(default)]
+    sealed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AckRequest {
+    /// Every message before this cursor has been received.
+    cursor: u64,
+    #[serde(default = "default_device")]
+    device: String,
+    #[serde(default)]
+    sealed: bool,
+}
+
+fn default_device() -> String {
+    DEFAULT_DEVICE.to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendRequest {
+    content: String,
+    #[serde(default)]
+    ttl: Option<u64>,
+    #[serde(default)]
+    burn_after_reading: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SendResponse {
+    delivery: Delivery,
+}
+
+/// List the messages waiting for the caller in a line, starting at `cursor`.
+/// Messages stay queued until every device of the caller has acknowledged them.
+#[get("/lines/{line_id}/messages")]
+pub async fn fetch_messages(
+    req: HttpRequest,
+    line_id: web::Path<u16>,
+    query: web::Query<FetchQuery>,
+    core: web::Data<Core>,
+) -> Result<web::Json<QueuedMessages>, Error> {
+    let sender = authenticated_sender(&req)?;
+    let limit = query.limit.unwrap_or(DEFAULT_FETCH_LIMIT).min(MAX_FETCH_LIMIT);
+    let queued = with_core(&core, move |core| core.fetch_queued(sender, &query.device, *line_id, query.sealed, query.cursor, limit)).await?;
+    Ok(web::Json(queued))
+}
+
+#[post("/lines/{line_id}/messages/ack")]
+pub async fn ack_messages(
+    req: HttpRequest,
+    line_id: web::Path<u16>,
+    body: web::Json<AckRequest>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    let sender = authenticated_sender(&req)?;
+    with_core(&core, move |core| core.ack_queued(sender, &body.device, *line_id, body.sealed, body.cursor)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/lines/{line_id}/messages")]
+pub async fn send_message(
+    req: HttpRequest,
+    line_id: web::Path<u16>,
+    body: web::Json<SendRequest>,
+    core: web::Data<Core>,
+) -> Result<web::Json<SendResponse>, Error> {
+    let sender = authenticated_sender(&req)?;
+    let SendRequest { content, ttl, burn_after_reading } = body.into_inner();
+    let message = Message {
+        line_id: *line_id,
+        sender: sender_to_string(sender).map_err(core_error)?,
+        content,
+        ttl,
+        burn_after_reading,
+        seq: None,
+    };
+    let behavior = with_core(&core, move |core| core.receive_message(&message)).await?;
+    Ok(web::Json(SendResponse { delivery: behavior.into() }))
+}
@@ -0,0 +1,11 @@
+use actix_web::{get, Error, HttpResponse, error::ErrorInternalServerError};
+use crate::libs::metrics::metrics;
+
+/// Serves what is already counted, see `watch_line_count`, without reaching storage.
+#[get("/metrics")]
+pub async fn get_metrics() -> Result<HttpResponse, Error> {
+    let body = metrics().gather().map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
@@ -1,2 +1,14 @@
 pub mod chat;
-mod profile;
\ No newline at end of file
+pub mod profile;
+pub mod messages;
+pub mod lines;
+pub mod admin;
+pub mod groups;
+pub mod blobs;
+pub mod push;
+pub mod sealed;
+pub mod metrics;
+pub mod health;
+mod auth;
+mod blocking;
+mod error;
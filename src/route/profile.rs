@@ -1,11 +1,70 @@
+use serde_derive::Serialize;
 use crate::libs::parse_config::Profile;
-use crate::libs::load_config::load_profile;
+use crate::libs::live_config::LiveConfig;
+use crate::libs::ws::{PROTOCOL_VERSIONS, ENCODINGS};
+use crate::libs::message::line_manage::LINE_MEMBERS;
 use actix_web::{get, web};
 
+/// Everything a client needs to know before connecting to `/ws/`.
+#[derive(Debug, Serialize)]
+pub struct ServerProfile {
+    #[serde(flatten)]
+    profile: Profile,
+    #[serde(rename = "Server Version")]
+    server_version: &'static str,
+    #[serde(rename = "Protocol Versions")]
+    protocol_versions: &'static [&'static str],
+    #[serde(rename = "Encodings")]
+    encodings: &'static [&'static str],
+    #[serde(rename = "Retention")]
+    retention: Retention,
+    #[serde(rename = "Limits")]
+    limits: Limits,
+    #[serde(rename = "Open Registration")]
+    open_registration: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Retention {
+    #[serde(rename = "Auto Delete")]
+    auto_delete: bool,
+    /// In seconds, absent when Auto Delete is off.
+    #[serde(rename = "Auto Delete Time")]
+    auto_delete_time: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Limits {
+    #[serde(rename = "Max Message Size")]
+    max_message_size: usize,
+    #[serde(rename = "Max Line Members")]
+    max_line_members: usize,
+    #[serde(rename = "Max Group Members")]
+    max_group_members: usize,
+    /// Largest blob an upload may grow to, in bytes.
+    #[serde(rename = "Max Blob Size")]
+    max_blob_size: u64,
+}
+
 #[get("/profile")]
-async fn get_profile() -> web::Json<Profile> {
-    match load_profile() {
-        Ok(profile) => web::Json(profile),
-        Err(_) => panic!("Failed to load profile."),
-    }
-}
\ No newline at end of file
+pub async fn get_profile(live_config: web::Data<LiveConfig>) -> web::Json<ServerProfile> {
+    let config = live_config.current();
+    let auto_delete_time = live_config.auto_delete_time().get();
+    web::Json(ServerProfile {
+        profile: config.profile.clone(),
+        server_version: env!("CARGO_PKG_VERSION"),
+        protocol_versions: PROTOCOL_VERSIONS,
+        encodings: ENCODINGS,
+        retention: Retention {
+            auto_delete: auto_delete_time.is_some(),
+            auto_delete_time,
+        },
+        limits: Limits {
+            max_message_size: config.config.max_message_size,
+            max_line_members: LINE_MEMBERS,
+            max_group_members: config.config.max_group_members,
+            max_blob_size: config.blobs.max_blob_size,
+        },
+        open_registration: config.config.open_registration,
+    })
+}
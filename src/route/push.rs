@@ -0,0 +1,35 @@
+use actix_web::{delete, put, web, Error, HttpRequest, HttpResponse};
+use serde_derive::Deserialize;
+use crate::libs::core::Core;
+use super::auth::authenticated_sender;
+use super::blocking::with_core;
+
+#[derive(Debug, Deserialize)]
+pub struct EndpointRequest {
+    /// URL the wake-ups are POSTed to, such as a UnifiedPush endpoint.
+    endpoint: String,
+}
+
+/// Wake `device` up whenever a message is queued for the caller.
+#[put("/push/endpoints/{device}")]
+pub async fn register_endpoint(
+    req: HttpRequest,
+    device: web::Path<String>,
+    body: web::Json<EndpointRequest>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    let sender = authenticated_sender(&req)?;
+    with_core(&core, move |core| core.register_push_endpoint(sender, &device, &body.endpoint)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/push/endpoints/{device}")]
+pub async fn unregister_endpoint(
+    req: HttpRequest,
+    device: web::Path<String>,
+    core: web::Data<Core>,
+) -> Result<HttpResponse, Error> {
+    let sender = authenticated_sender(&req)?;
+    with_core(&core, move |core| core.unregister_push_endpoint(sender, &device)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,61 @@
+use actix_web::{get, post, web, Error, HttpRequest};
+use serde_derive::{Deserialize, Serialize};
+use crate::libs::core::Core;
+use crate::libs::ws::ws_response::Delivery;
+use crate::libs::message::Message;
+use super::auth::authenticated_sender;
+use super::blocking::with_core;
+
+#[derive(Debug, Serialize)]
+pub struct DeliveryTokenResponse {
+    delivery_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SealedSendRequest {
+    delivery_token: String,
+    /// Names the sender, encrypted for the recipient only.
+    content: String,
+    #[serde(default)]
+    ttl: Option<u64>,
+    #[serde(default)]
+    burn_after_reading: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SealedSendResponse {
+    delivery: Delivery,
+}
+
+/// The token the caller hands to its peers so they can send it sealed messages in a line.
+#[get("/lines/{line_id}/delivery-token")]
+pub async fn get_delivery_token(
+    req: HttpRequest,
+    line_id: web::Path<u16>,
+    core: web::Data<Core>,
+) -> Result<web::Json<DeliveryTokenResponse>, Error> {
+    let sender = authenticated_sender(&req)?;
+    let delivery_token = with_core(&core, move |core| core.delivery_token(sender, *line_id)).await?;
+    Ok(web::Json(DeliveryTokenResponse { delivery_token }))
+}
+
+/// Send without authenticating, the delivery token is the only credential.
+/// The recipient reads these with `sealed=true` on its message endpoints.
+#[post("/sealed/lines/{line_id}/messages")]
+pub async fn send_sealed(
+    line_id: web::Path<u16>,
+    body: web::Json<SealedSendRequest>,
+    core: web::Data<Core>,
+) -> Result<web::Json<SealedSendResponse>, Error> {
+    let SealedSendRequest { delivery_token, content, ttl, burn_after_reading } = body.into_inner();
+    let message = Message {
+        line_id: *line_id,
+        sender: String::new(),
+        content,
+        ttl,
+        burn_after_reading,
+        seq: None,
+    };
+    let behavior = with_core(&core, move |core| core.receive_sealed(&delivery_token, &message)).await?;
+    Ok(web::Json(SealedSendResponse { delivery: behavior.into() }))
+}